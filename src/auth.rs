@@ -0,0 +1,149 @@
+use salvo::prelude::*;
+use serde::Deserialize;
+use time::OffsetDateTime;
+
+use crate::error::{AppError, AppResult};
+use crate::jwt::{Claims, JwtConfig};
+use crate::AppConfig;
+
+/// Credentials accepted by the API. Authentication is on as soon as one of
+/// them is configured.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Keys sent in the `X-API-Key` header.
+    pub api_keys: Vec<String>,
+    /// Tokens sent as `Authorization: Bearer <token>`.
+    pub tokens: Vec<BearerToken>,
+    /// Login flow issuing JWTs, also accepted as bearer tokens.
+    pub jwt: Option<JwtConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BearerToken {
+    pub token: String,
+    /// Rejected as expired from then on.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub expires_at: Option<OffsetDateTime>,
+}
+
+impl BearerToken {
+    pub fn new(token: String) -> Self {
+        Self {
+            token,
+            expires_at: None,
+        }
+    }
+}
+
+impl AuthConfig {
+    /// Whether API keys or static tokens are configured, in which case
+    /// requests need credentials unless the access rules say otherwise.
+    pub fn is_enabled(&self) -> bool {
+        !self.api_keys.is_empty() || !self.tokens.is_empty()
+    }
+
+    /// Checks the credentials of `req`, if any: an `X-API-Key` header, a
+    /// bearer token or, for clients that cannot set headers, an
+    /// `access_token` query parameter.
+    fn check(&self, req: &Request) -> AppResult<Caller> {
+        if !self.is_enabled() && self.jwt.is_none() {
+            return Ok(Caller::Anonymous);
+        }
+
+        if let Some(key) = req.header::<String>("x-api-key") {
+            return if self.api_keys.contains(&key) {
+                Ok(Caller::Client)
+            } else {
+                Err(AppError::InvalidToken("unknown API key".to_string()))
+            };
+        }
+
+        let Some(token) = bearer_token(req) else {
+            return Ok(Caller::Anonymous);
+        };
+
+        match self.tokens.iter().find(|known| known.token == token) {
+            Some(known)
+                if known
+                    .expires_at
+                    .is_some_and(|at| at <= OffsetDateTime::now_utc()) =>
+            {
+                Err(AppError::InvalidToken(
+                    "the access token expired".to_string(),
+                ))
+            }
+            Some(_) => Ok(Caller::Client),
+            None => match &self.jwt {
+                Some(jwt) => jwt.verify(&token).map(Caller::User),
+                None => Err(AppError::InvalidToken("unknown access token".to_string())),
+            },
+        }
+    }
+}
+
+/// The bearer token of `req`, from its `Authorization` header or its
+/// `access_token` query parameter.
+pub fn bearer_token(req: &Request) -> Option<String> {
+    req.header::<String>("authorization")
+        .and_then(|value| {
+            value
+                .strip_prefix("Bearer ")
+                .map(|token| token.trim().to_string())
+        })
+        .or_else(|| req.query::<String>("access_token"))
+}
+
+/// Who a request comes from, as told by its credentials.
+#[derive(Clone, Debug)]
+pub enum Caller {
+    Anonymous,
+    /// A client with an API key or a static bearer token.
+    Client,
+    /// A user signed in with a JWT.
+    User(Claims),
+}
+
+impl Caller {
+    pub fn user(&self) -> Option<&Claims> {
+        match self {
+            Caller::User(claims) => Some(claims),
+            _ => None,
+        }
+    }
+}
+
+/// The caller found by [`authenticate`] for the request.
+pub fn caller(depot: &Depot) -> Caller {
+    depot
+        .obtain::<Caller>()
+        .map_or(Caller::Anonymous, Clone::clone)
+}
+
+/// Finds out who the request comes from, rejecting unknown or expired
+/// credentials with `401 Unauthorized`.
+///
+/// The [`Caller`] is left in the depot for the access rules and handlers,
+/// which turn away anonymous requests when authentication is on.
+#[handler]
+pub async fn authenticate(req: &mut Request, depot: &mut Depot) -> AppResult<()> {
+    let app_config = depot.obtain::<AppConfig>().unwrap();
+    let caller = app_config.auth.check(req)?;
+    depot.inject(caller);
+    Ok(())
+}
+
+/// Rejects anonymous requests with `401 Unauthorized` when authentication is
+/// on, for routes outside of the access rules.
+#[handler]
+pub async fn require_credentials(depot: &mut Depot) -> AppResult<()> {
+    let app_config = depot.obtain::<AppConfig>().unwrap();
+    let anonymous = matches!(caller(depot), Caller::Anonymous);
+    if app_config.auth.is_enabled() && anonymous {
+        return Err(AppError::Unauthorized(
+            "missing API key or bearer token".to_string(),
+        ));
+    }
+    Ok(())
+}
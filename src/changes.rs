@@ -0,0 +1,332 @@
+use salvo::async_trait;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+use crate::error::AppResult;
+use crate::history::History;
+use crate::jwt::strip_password;
+use crate::storage::{item_id, Storage};
+use crate::trash::is_deleted;
+
+/// How many changes a slow subscriber may fall behind before missing some.
+const CHANGE_FEED_CAPACITY: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operation {
+    Create,
+    Update,
+    Delete,
+    /// A soft-deleted item was brought back.
+    Restore,
+    /// The whole collection was replaced or deleted.
+    Reset,
+}
+
+impl Operation {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Operation::Create => "create",
+            Operation::Update => "update",
+            Operation::Delete => "delete",
+            Operation::Restore => "restore",
+            Operation::Reset => "reset",
+        }
+    }
+
+    /// Name of the event announcing a change of this kind.
+    pub fn event(self) -> &'static str {
+        match self {
+            Operation::Create => "created",
+            Operation::Update => "updated",
+            Operation::Delete => "deleted",
+            Operation::Restore => "restored",
+            Operation::Reset => "reset",
+        }
+    }
+}
+
+/// A change made to collection `collection`, with the item before and after
+/// it when they are known.
+#[derive(Clone, Debug)]
+pub struct Change {
+    pub collection: String,
+    pub operation: Operation,
+    pub id: Option<u64>,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+}
+
+impl Change {
+    /// The item as it is after the change, or as it was before being deleted.
+    pub fn item(&self) -> Option<&serde_json::Value> {
+        self.after.as_ref().or(self.before.as_ref())
+    }
+
+    /// Body of the notifications sent to subscribers.
+    pub fn payload(&self) -> serde_json::Value {
+        serde_json::json!({
+            "collection": self.collection,
+            "id": self.id,
+            "item": self.item(),
+        })
+    }
+}
+
+/// Broadcasts every [`Change`] to the clients following collections.
+pub type ChangeFeed = broadcast::Sender<Change>;
+
+pub fn change_feed() -> ChangeFeed {
+    broadcast::channel(CHANGE_FEED_CAPACITY).0
+}
+
+/// Wraps a storage backend to record every item it creates, updates or
+/// deletes in the [`History`], if it is kept, and publish it on the
+/// [`ChangeFeed`].
+#[derive(Debug)]
+pub struct TrackedStorage {
+    inner: Arc<dyn Storage>,
+    history: Option<Arc<History>>,
+    feed: ChangeFeed,
+    /// Collection of the users of the login flow, whose password hashes are
+    /// kept out of the feed.
+    users: Option<String>,
+    /// Whether patches setting or clearing `deletedAt` are deletes and
+    /// restores rather than updates.
+    soft_delete: bool,
+}
+
+impl TrackedStorage {
+    pub fn new(inner: Arc<dyn Storage>, history: Option<Arc<History>>, feed: ChangeFeed) -> Self {
+        Self {
+            inner,
+            history,
+            feed,
+            users: None,
+            soft_delete: false,
+        }
+    }
+
+    pub fn with_users(mut self, users: Option<String>) -> Self {
+        self.users = users;
+        self
+    }
+
+    pub fn with_soft_delete(mut self, soft_delete: bool) -> Self {
+        self.soft_delete = soft_delete;
+        self
+    }
+
+    /// What a patch turning `before` into `after` did to the item.
+    fn patch_operation(
+        &self,
+        before: Option<&serde_json::Value>,
+        after: &serde_json::Value,
+    ) -> Operation {
+        if !self.soft_delete {
+            return Operation::Update;
+        }
+        match (before.is_some_and(is_deleted), is_deleted(after)) {
+            (false, true) => Operation::Delete,
+            (true, false) => Operation::Restore,
+            _ => Operation::Update,
+        }
+    }
+
+    /// The item as it was before a change, only read when somebody is
+    /// interested in it.
+    async fn before(&self, f: &str, id: u64) -> Option<serde_json::Value> {
+        if self.history.is_none() && self.feed.receiver_count() == 0 {
+            return None;
+        }
+        self.inner.get(f, id).await.ok()
+    }
+
+    async fn publish(
+        &self,
+        f: &str,
+        operation: Operation,
+        id: Option<u64>,
+        before: Option<serde_json::Value>,
+        after: Option<serde_json::Value>,
+    ) -> AppResult<()> {
+        let mut change = Change {
+            collection: f.to_string(),
+            operation,
+            id,
+            before,
+            after,
+        };
+        if let Some(history) = &self.history {
+            history.record(&change).await?;
+        }
+        // The history keeps password hashes, for rollbacks to restore them.
+        if self.users.as_deref() == Some(f) {
+            change.before.iter_mut().for_each(strip_password);
+            change.after.iter_mut().for_each(strip_password);
+        }
+        // Nobody following the feed is not an error.
+        let _ = self.feed.send(change);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for TrackedStorage {
+    fn location(&self) -> Option<&str> {
+        self.inner.location()
+    }
+
+    async fn list_collections(&self) -> AppResult<Vec<String>> {
+        self.inner.list_collections().await
+    }
+
+    async fn get_all(&self, f: &str) -> AppResult<Vec<serde_json::Value>> {
+        self.inner.get_all(f).await
+    }
+
+    async fn get(&self, f: &str, id: u64) -> AppResult<serde_json::Value> {
+        self.inner.get(f, id).await
+    }
+
+    async fn insert(&self, f: &str, new_item: serde_json::Value) -> AppResult<serde_json::Value> {
+        let new_item = self.inner.insert(f, new_item).await?;
+        self.publish(
+            f,
+            Operation::Create,
+            item_id(&new_item),
+            None,
+            Some(new_item.clone()),
+        )
+        .await?;
+        Ok(new_item)
+    }
+
+    async fn replace_if(
+        &self,
+        f: &str,
+        id: u64,
+        expected: Option<&serde_json::Value>,
+        updated_item: &serde_json::Value,
+    ) -> AppResult<bool> {
+        let before = self.before(f, id).await;
+        let found_item = self.inner.replace_if(f, id, expected, updated_item).await?;
+        if found_item {
+            self.publish(
+                f,
+                Operation::Update,
+                Some(id),
+                before,
+                Some(updated_item.clone()),
+            )
+            .await?;
+        }
+        Ok(found_item)
+    }
+
+    async fn patch_if(
+        &self,
+        f: &str,
+        id: u64,
+        expected: Option<&serde_json::Value>,
+        patch: &serde_json::Value,
+    ) -> AppResult<Option<serde_json::Value>> {
+        let before = self.before(f, id).await;
+        let patched_item = self.inner.patch_if(f, id, expected, patch).await?;
+        if let Some(patched_item) = &patched_item {
+            self.publish(
+                f,
+                self.patch_operation(before.as_ref(), patched_item),
+                Some(id),
+                before,
+                Some(patched_item.clone()),
+            )
+            .await?;
+        }
+        Ok(patched_item)
+    }
+
+    async fn delete_if(
+        &self,
+        f: &str,
+        id: u64,
+        expected: Option<&serde_json::Value>,
+    ) -> AppResult<bool> {
+        let before = self.before(f, id).await;
+        let found_item = self.inner.delete_if(f, id, expected).await?;
+        if found_item {
+            self.publish(f, Operation::Delete, Some(id), before, None)
+                .await?;
+        }
+        Ok(found_item)
+    }
+
+    async fn count(&self, f: &str) -> AppResult<usize> {
+        self.inner.count(f).await
+    }
+
+    async fn replace_collection(&self, f: &str, items: Vec<serde_json::Value>) -> AppResult<()> {
+        self.inner.replace_collection(f, items).await?;
+        self.publish(f, Operation::Reset, None, None, None).await
+    }
+
+    async fn delete_collection(&self, f: &str) -> AppResult<()> {
+        self.inner.delete_collection(f).await?;
+        self.publish(f, Operation::Reset, None, None, None).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use serde_json::json;
+
+    async fn patch_operations(soft_delete: bool) -> Vec<Operation> {
+        let feed = change_feed();
+        let mut changes = feed.subscribe();
+        let storage = TrackedStorage::new(Arc::new(MemoryStorage::default()), None, feed)
+            .with_soft_delete(soft_delete);
+        storage.insert("posts", json!({ "id": 1 })).await.unwrap();
+        for patch in [
+            json!({ "title": "a" }),
+            json!({ "deletedAt": "2024-01-01T00:00:00Z" }),
+            json!({ "title": "b" }),
+            json!({ "deletedAt": null }),
+        ] {
+            storage.patch("posts", 1, &patch).await.unwrap();
+        }
+        let mut operations = Vec::new();
+        while let Ok(change) = changes.try_recv() {
+            operations.push(change.operation);
+        }
+        operations
+    }
+
+    #[tokio::test]
+    async fn publishes_soft_deletes_and_restores() {
+        assert_eq!(
+            patch_operations(true).await,
+            [
+                Operation::Create,
+                Operation::Update,
+                Operation::Delete,
+                Operation::Update,
+                Operation::Restore,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn publishes_updates_without_soft_deletes() {
+        assert_eq!(
+            patch_operations(false).await,
+            [
+                Operation::Create,
+                Operation::Update,
+                Operation::Update,
+                Operation::Update,
+                Operation::Update,
+            ]
+        );
+    }
+}
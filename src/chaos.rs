@@ -0,0 +1,225 @@
+use rand::RngExt;
+use salvo::prelude::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::error::{AppError, AppResult};
+use crate::AppConfig;
+
+/// Request header delaying its response, e.g. `X-Mock-Delay: 200..800ms`.
+const MOCK_DELAY: &str = "x-mock-delay";
+
+/// Request header answering it with a given error status without handling it.
+const MOCK_STATUS: &str = "x-mock-status";
+
+/// A pause before answering: fixed, or drawn uniformly from a range.
+///
+/// Written `300`, `300ms`, `2s`, or as a range like `200..800ms`; numbers
+/// without a unit are milliseconds.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(try_from = "DelaySpec")]
+pub struct Delay {
+    min: Duration,
+    max: Duration,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DelaySpec {
+    Millis(u64),
+    Text(String),
+}
+
+impl TryFrom<DelaySpec> for Delay {
+    type Error = String;
+
+    fn try_from(spec: DelaySpec) -> Result<Self, Self::Error> {
+        match spec {
+            DelaySpec::Millis(millis) => Ok(Self {
+                min: Duration::from_millis(millis),
+                max: Duration::from_millis(millis),
+            }),
+            DelaySpec::Text(text) => text.parse(),
+        }
+    }
+}
+
+impl FromStr for Delay {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            || format!("invalid delay `{value}`: expected e.g. `300ms`, `2s` or `200..800ms`");
+        let value = value.trim();
+        let (number, unit) = if let Some(number) = value.strip_suffix("ms") {
+            (number, 1)
+        } else if let Some(number) = value.strip_suffix('s') {
+            (number, 1000)
+        } else {
+            (value, 1)
+        };
+        let parse = |number: &str| {
+            let millis = number.trim().parse::<u64>().ok()?.checked_mul(unit)?;
+            Some(Duration::from_millis(millis))
+        };
+
+        let (min, max) = match number.split_once("..") {
+            Some((min, max)) => (parse(min), parse(max)),
+            None => (parse(number), parse(number)),
+        };
+        match (min, max) {
+            (Some(min), Some(max)) if min <= max => Ok(Self { min, max }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Delay {
+    fn sample(&self) -> Duration {
+        if self.min == self.max {
+            return self.min;
+        }
+        rand::rng().random_range(self.min..=self.max)
+    }
+}
+
+/// Faults injected into responses, to exercise the retry and loading logic
+/// of clients. Set from the `chaos` section of the `--config` file and the
+/// `--delay`, `--failure-rate`, `--failure-status` and `--drop-rate` flags.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Chaos {
+    pub delay: Option<Delay>,
+    /// Percentage of requests answered with one of `failure_statuses`
+    /// instead of being handled.
+    pub failure_rate: f64,
+    /// Statuses of the injected failures, `500` when empty.
+    pub failure_statuses: Vec<u16>,
+    /// Percentage of requests whose connection is dropped without an answer.
+    pub drop_rate: f64,
+    /// Settings replacing these for the paths starting with each key; the
+    /// longest matching prefix wins.
+    pub routes: HashMap<String, Chaos>,
+}
+
+impl Chaos {
+    /// Checks the failure statuses, here and in every route.
+    pub fn validate(&self) -> AppResult<()> {
+        let statuses = self
+            .routes
+            .values()
+            .flat_map(|route| &route.failure_statuses);
+        for status in self.failure_statuses.iter().chain(statuses) {
+            failure_status(*status)?;
+        }
+        Ok(())
+    }
+
+    /// The settings applying to requests for `path`.
+    fn for_path(&self, path: &str) -> &Chaos {
+        self.routes
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self, |(_, route)| route)
+    }
+
+    fn failure(&self) -> AppResult<StatusCode> {
+        if self.failure_statuses.is_empty() {
+            return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        let index = rand::rng().random_range(0..self.failure_statuses.len());
+        failure_status(self.failure_statuses[index])
+    }
+}
+
+/// `status` as a `4xx` or `5xx` status code.
+fn failure_status(status: u16) -> AppResult<StatusCode> {
+    StatusCode::from_u16(status)
+        .ok()
+        .filter(|status| status.is_client_error() || status.is_server_error())
+        .ok_or_else(|| {
+            AppError::BadRequest(format!(
+                "invalid failure status `{status}`: expected a 4xx or 5xx status"
+            ))
+        })
+}
+
+/// Whether an event happening `rate` percent of the time happens now.
+fn chance(rate: f64) -> bool {
+    rate > 0.0 && rand::rng().random_range(0.0..100.0) < rate
+}
+
+/// Delays requests and makes some of them fail or drop, as the chaos
+/// settings of their path and their `X-Mock-Delay`/`X-Mock-Status` headers
+/// say.
+#[handler]
+pub async fn inject(req: &mut Request, depot: &mut Depot) -> AppResult<()> {
+    let app_config = depot.obtain::<AppConfig>().unwrap();
+    let chaos = app_config.chaos.for_path(req.uri().path());
+
+    let delay = match req.header::<String>(MOCK_DELAY) {
+        Some(delay) => Some(delay.parse::<Delay>().map_err(AppError::BadRequest)?),
+        None => chaos.delay,
+    };
+    if let Some(delay) = delay {
+        tokio::time::sleep(delay.sample()).await;
+    }
+
+    if let Some(status) = req.header::<String>(MOCK_STATUS) {
+        let status = status
+            .parse()
+            .map_err(|_| AppError::BadRequest(format!("invalid `X-Mock-Status` `{status}`")))?;
+        return Err(AppError::Injected(failure_status(status)?));
+    }
+    if chance(chaos.drop_rate) {
+        return Err(AppError::ConnectionDropped);
+    }
+    if chance(chaos.failure_rate) {
+        return Err(AppError::Injected(chaos.failure()?));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delay(value: &str) -> (Duration, Duration) {
+        let delay = value.parse::<Delay>().unwrap();
+        (delay.min, delay.max)
+    }
+
+    #[test]
+    fn parses_fixed_delays() {
+        let ms = Duration::from_millis;
+        assert_eq!(delay("300"), (ms(300), ms(300)));
+        assert_eq!(delay("300ms"), (ms(300), ms(300)));
+        assert_eq!(delay(" 2s "), (ms(2000), ms(2000)));
+        assert_eq!(delay("0"), (ms(0), ms(0)));
+    }
+
+    #[test]
+    fn parses_ranges() {
+        let ms = Duration::from_millis;
+        assert_eq!(delay("200..800ms"), (ms(200), ms(800)));
+        assert_eq!(delay("1..2s"), (ms(1000), ms(2000)));
+        assert_eq!(delay("5..5"), (ms(5), ms(5)));
+    }
+
+    #[test]
+    fn rejects_invalid_delays() {
+        for value in ["", "ms", "-1", "1.5s", "2m", "800..200ms", "1..", "..1"] {
+            assert!(value.parse::<Delay>().is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn rejects_overflowing_delays() {
+        assert!("99999999999999999s".parse::<Delay>().is_err());
+        assert!("1..99999999999999999s".parse::<Delay>().is_err());
+        assert!("18446744073709551615ms".parse::<Delay>().is_ok());
+    }
+}
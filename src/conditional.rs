@@ -0,0 +1,124 @@
+use etag::EntityTag;
+use salvo::http::header::{HeaderName, ETAG, IF_MATCH, IF_NONE_MATCH};
+use salvo::prelude::*;
+
+use crate::error::{AppError, AppResult};
+
+/// Strong entity tag of a response body, hashed from its JSON encoding.
+pub fn etag(body: &impl serde::Serialize) -> AppResult<EntityTag> {
+    Ok(EntityTag::from_data(&serde_json::to_vec(body)?))
+}
+
+/// Adds the `ETag` of `tag` to the response and reports whether the
+/// request's `If-None-Match` already holds it, in which case the response
+/// is turned into an empty `304 Not Modified`.
+pub fn not_modified(req: &Request, res: &mut Response, tag: &EntityTag) -> AppResult<bool> {
+    res.add_header(ETAG, tag.to_string(), true)?;
+
+    let unchanged = condition(req, IF_NONE_MATCH)
+        .is_some_and(|tags| tags.matches(|candidate| candidate.weak_eq(tag)));
+    if unchanged {
+        res.status_code(StatusCode::NOT_MODIFIED);
+    }
+    Ok(unchanged)
+}
+
+/// Checks the `If-Match` header of a write against the `current` version of
+/// the item, `None` when it does not exist. Returns whether there was such
+/// a header, in which case the write must only happen if the item is still
+/// `current`.
+///
+/// Fails with `412 Precondition Failed` when the tags differ, and with
+/// `428 Precondition Required` when `required` and the header is missing.
+pub fn check_if_match(
+    req: &Request,
+    current: Option<&serde_json::Value>,
+    required: bool,
+) -> AppResult<bool> {
+    let Some(tags) = condition(req, IF_MATCH) else {
+        if required {
+            return Err(AppError::PreconditionRequired(
+                "this request must carry an `If-Match` header".to_string(),
+            ));
+        }
+        return Ok(false);
+    };
+    check_tags(&tags, current)?;
+    Ok(true)
+}
+
+/// Checks the `if_match` of a WebSocket write as `check_if_match` does the
+/// header of a request.
+pub fn check_if_match_field(
+    if_match: Option<&str>,
+    current: Option<&serde_json::Value>,
+    required: bool,
+) -> AppResult<bool> {
+    let Some(if_match) = if_match else {
+        if required {
+            return Err(AppError::PreconditionRequired(
+                "this message must carry an `if_match` field".to_string(),
+            ));
+        }
+        return Ok(false);
+    };
+    check_tags(&parse_condition([if_match]), current)?;
+    Ok(true)
+}
+
+/// Checks that `tags` hold the entity tag of `current`.
+fn check_tags(tags: &Condition, current: Option<&serde_json::Value>) -> AppResult<()> {
+    let current = current.map(etag).transpose()?;
+    let matched = current
+        .as_ref()
+        .is_some_and(|current| tags.matches(|candidate| candidate.strong_eq(current)));
+    if matched {
+        Ok(())
+    } else {
+        Err(AppError::PreconditionFailed(match current {
+            Some(current) => format!("`If-Match` does not match the current ETag {current}"),
+            None => "`If-Match` given for an item that does not exist".to_string(),
+        }))
+    }
+}
+
+/// The entity tags listed by a conditional request header.
+enum Condition {
+    Any,
+    Tags(Vec<EntityTag>),
+}
+
+impl Condition {
+    fn matches(&self, eq: impl Fn(&EntityTag) -> bool) -> bool {
+        match self {
+            Condition::Any => true,
+            Condition::Tags(tags) => tags.iter().any(eq),
+        }
+    }
+}
+
+fn condition(req: &Request, name: HeaderName) -> Option<Condition> {
+    let values: Vec<&str> = req
+        .headers()
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    if values.is_empty() {
+        return None;
+    }
+    Some(parse_condition(values))
+}
+
+/// Reads the comma separated entity tags of the `values` of a header.
+fn parse_condition<'a>(values: impl IntoIterator<Item = &'a str>) -> Condition {
+    let tags: Vec<&str> = values
+        .into_iter()
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    if tags.contains(&"*") {
+        return Condition::Any;
+    }
+    Condition::Tags(tags.iter().filter_map(|tag| tag.parse().ok()).collect())
+}
@@ -0,0 +1,33 @@
+use serde::Deserialize;
+
+use crate::auth::AuthConfig;
+use crate::chaos::Chaos;
+use crate::cors::CorsConfig;
+use crate::error::AppResult;
+use crate::rate_limit::RateLimitConfig;
+use crate::relations::Relationship;
+use crate::timestamps::Timestamps;
+use crate::webhooks::Webhook;
+
+/// Settings read from the JSON file given with `--config`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    pub auth: AuthConfig,
+    pub chaos: Chaos,
+    pub cors: CorsConfig,
+    pub rate_limit: RateLimitConfig,
+    pub relationships: Vec<Relationship>,
+    /// Field names and format of the bookkeeping fields; setting this section
+    /// turns them on, like `--timestamps`.
+    pub timestamps: Option<Timestamps>,
+    /// Webhooks registered at startup, next to those added through `/_webhooks`.
+    pub webhooks: Vec<Webhook>,
+}
+
+impl FileConfig {
+    pub fn load(path: &str) -> AppResult<Self> {
+        let json_string = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json_string)?)
+    }
+}
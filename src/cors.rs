@@ -0,0 +1,120 @@
+use salvo::cors::{self, AllowHeaders, AllowMethods, AllowOrigin, Cors, CorsHandler};
+use salvo::http::{HeaderName, HeaderValue, Method};
+use salvo::prelude::*;
+use serde::Deserialize;
+
+use crate::error::{AppError, AppResult};
+
+/// Response headers browsers let scripts read.
+const EXPOSE_HEADERS: [&str; 9] = [
+    "x-total-count",
+    "link",
+    "etag",
+    "www-authenticate",
+    "retry-after",
+    "ratelimit-limit",
+    "ratelimit-remaining",
+    "ratelimit-reset",
+    "ratelimit-policy",
+];
+
+/// Cross-origin policy of the server. `*` allows any origin, method or
+/// header; with `credentials`, it mirrors the request instead, as browsers
+/// refuse wildcards on credentialed requests.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    pub origins: Vec<String>,
+    pub methods: Vec<String>,
+    pub headers: Vec<String>,
+    /// Whether cookies and `Authorization` headers may be sent.
+    pub credentials: bool,
+    /// How long browsers may cache a preflight response, in seconds.
+    pub max_age: Option<u64>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            origins: vec!["*".to_string()],
+            methods: vec!["*".to_string()],
+            headers: vec!["*".to_string()],
+            credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+fn is_any(values: &[String]) -> bool {
+    values.iter().any(|value| value == "*")
+}
+
+fn invalid(what: &str, value: &str) -> AppError {
+    AppError::BadRequest(format!("invalid CORS {what} `{value}`"))
+}
+
+impl CorsConfig {
+    pub fn handler(&self) -> AppResult<CorsHandler> {
+        let origins = if !is_any(&self.origins) {
+            let origins = self
+                .origins
+                .iter()
+                .map(|origin| HeaderValue::from_str(origin).map_err(|_| invalid("origin", origin)))
+                .collect::<AppResult<Vec<_>>>()?;
+            AllowOrigin::list(origins)
+        } else if self.credentials {
+            AllowOrigin::mirror_request()
+        } else {
+            cors::Any.into()
+        };
+
+        let methods = if !is_any(&self.methods) {
+            let methods = self
+                .methods
+                .iter()
+                .map(|method| {
+                    Method::from_bytes(method.to_uppercase().as_bytes())
+                        .map_err(|_| invalid("method", method))
+                })
+                .collect::<AppResult<Vec<_>>>()?;
+            AllowMethods::list(methods)
+        } else if self.credentials {
+            AllowMethods::mirror_request()
+        } else {
+            cors::Any.into()
+        };
+
+        let headers = if !is_any(&self.headers) {
+            let headers = self
+                .headers
+                .iter()
+                .map(|header| HeaderName::try_from(header).map_err(|_| invalid("header", header)))
+                .collect::<AppResult<Vec<_>>>()?;
+            AllowHeaders::list(headers)
+        } else if self.credentials {
+            AllowHeaders::mirror_request()
+        } else {
+            cors::Any.into()
+        };
+
+        let mut cors = Cors::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers(headers)
+            .allow_credentials(self.credentials)
+            .expose_headers(EXPOSE_HEADERS.to_vec());
+        if let Some(max_age) = self.max_age {
+            cors = cors.max_age(max_age);
+        }
+        Ok(cors.into_handler())
+    }
+}
+
+/// Answers CORS preflight requests with the headers set by the CORS handler,
+/// before the other hoops can delay or turn them away.
+#[handler]
+pub async fn preflight(req: &mut Request, ctrl: &mut FlowCtrl) {
+    if req.method() == Method::OPTIONS {
+        ctrl.skip_rest();
+    }
+}
@@ -1,7 +1,11 @@
+use futures_util::stream;
+use salvo::http::header::{RETRY_AFTER, WWW_AUTHENTICATE};
 use salvo::prelude::*;
 use std::io;
 use thiserror::Error;
 
+use crate::schema::FieldError;
+
 #[derive(Error, Debug)]
 pub enum AppError {
     #[error("io: `{0}`")]
@@ -13,17 +17,109 @@ pub enum AppError {
     #[error("error parsing request: `{0}`")]
     ParseError(#[from] salvo::http::ParseError),
 
+    #[error("http: `{0}`")]
+    Http(#[from] salvo::Error),
+
+    #[error("sqlite: `{0}`")]
+    Sqlite(#[from] rusqlite::Error),
+
     // #[error("Failed to generate random ID")]
     // RandomIdGeneration,
     #[error("Item not found with ID: {0}")]
     ItemNotFound(u64),
+
+    #[error("bad request: {0}")]
+    BadRequest(String),
+
+    #[error("conflict: {0}")]
+    Conflict(String),
+
+    #[error("unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("invalid token: {0}")]
+    InvalidToken(String),
+
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("precondition failed: {0}")]
+    PreconditionFailed(String),
+
+    #[error("precondition required: {0}")]
+    PreconditionRequired(String),
+
+    #[error("invalid schema: {0}")]
+    Schema(String),
+
+    #[error("validation failed")]
+    Validation(Vec<FieldError>),
+
+    #[error("too many requests, retry in {0}s")]
+    TooManyRequests(u64),
+
+    #[error("injected failure")]
+    Injected(StatusCode),
+
+    #[error("connection dropped")]
+    ConnectionDropped,
 }
 
 pub type AppResult<T> = Result<T, AppError>;
 
+/// Protection space named in `WWW-Authenticate` challenges.
+const REALM: &str = "static-api";
+
 #[async_trait]
 impl Writer for AppError {
     async fn write(mut self, _req: &mut Request, _depot: &mut Depot, res: &mut Response) {
+        match self {
+            AppError::BadRequest(_) => res.status_code(StatusCode::BAD_REQUEST),
+            AppError::Conflict(_) => res.status_code(StatusCode::CONFLICT),
+            AppError::Unauthorized(_) => {
+                let _ = res.add_header(WWW_AUTHENTICATE, format!("Bearer realm=\"{REALM}\""), true);
+                res.status_code(StatusCode::UNAUTHORIZED)
+            }
+            AppError::InvalidToken(ref message) => {
+                let challenge = format!(
+                    "Bearer realm=\"{REALM}\", error=\"invalid_token\", error_description=\"{message}\""
+                );
+                let _ = res.add_header(WWW_AUTHENTICATE, challenge, true);
+                res.status_code(StatusCode::UNAUTHORIZED)
+            }
+            AppError::Forbidden(_) => res.status_code(StatusCode::FORBIDDEN),
+            AppError::PreconditionFailed(_) => res.status_code(StatusCode::PRECONDITION_FAILED),
+            AppError::PreconditionRequired(_) => {
+                res.status_code(StatusCode::PRECONDITION_REQUIRED)
+            }
+            AppError::Schema(_) => res.status_code(StatusCode::INTERNAL_SERVER_ERROR),
+            AppError::TooManyRequests(retry_after) => {
+                let _ = res.add_header(RETRY_AFTER, retry_after, true);
+                res.status_code(StatusCode::TOO_MANY_REQUESTS)
+            }
+            AppError::Injected(status) => res.status_code(status),
+            AppError::ConnectionDropped => {
+                // A body failing before its first byte makes the server close
+                // the connection without an answer.
+                res.stream(stream::once(async {
+                    Err::<Vec<u8>, _>(io::Error::other("connection dropped"))
+                }));
+                return;
+            }
+            AppError::Validation(errors) => {
+                res.status_code(StatusCode::UNPROCESSABLE_ENTITY);
+                res.render(Json(serde_json::json!({
+                    "message": "validation failed",
+                    "errors": errors,
+                })));
+                return;
+            }
+            AppError::ParseError(_) => res.status_code(StatusCode::BAD_REQUEST),
+            AppError::ItemNotFound(_) => res.status_code(StatusCode::NOT_FOUND),
+            AppError::Io(_) | AppError::JsonParse(_) | AppError::Http(_) | AppError::Sqlite(_) => {
+                res.status_code(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        };
         res.render(Text::Plain(self.to_string()));
     }
 }
@@ -0,0 +1,50 @@
+use futures_util::stream;
+use salvo::prelude::*;
+use salvo::sse::{SseEvent, SseKeepAlive};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::changes::Change;
+use crate::jwt::current_user;
+use crate::ops;
+use crate::AppConfig;
+
+/// Streams the changes made to collection `f` as Server-Sent Events named
+/// after the operation (`created`, `updated`, `deleted` or `reset`), leaving
+/// out those of items owned by other users.
+#[handler]
+pub async fn collection_events(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+    let app_config = depot.obtain::<AppConfig>().unwrap().clone();
+    let file_path = req.param::<String>("f").unwrap();
+    let user = current_user(depot);
+    let receiver = app_config.changes.subscribe();
+
+    let events = stream::unfold(receiver, move |mut receiver| {
+        let app_config = app_config.clone();
+        let file_path = file_path.clone();
+        let user = user.clone();
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(change) if change.collection == file_path => {
+                        let foreign = change.item().is_some_and(|item| {
+                            ops::foreign(&app_config, &file_path, item, user.as_ref())
+                        });
+                        if !foreign {
+                            return Some((event(&change), receiver));
+                        }
+                    }
+                    // A lagging client misses the changes it fell behind on.
+                    Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+    SseKeepAlive::new(events).stream(res);
+}
+
+fn event(change: &Change) -> Result<SseEvent, serde_json::Error> {
+    SseEvent::default()
+        .name(change.operation.event())
+        .json(change.payload())
+}
@@ -0,0 +1,55 @@
+use salvo::prelude::*;
+
+use crate::pagination::PAGE_PARAMS;
+
+/// Query parameter carrying a bearer token, not a field.
+const ACCESS_TOKEN: &str = "access_token";
+
+/// Field values the items of a listing must have, from `?field=value` query
+/// parameters. Parameters starting with `_`, those selecting the page and
+/// `access_token` are not fields; a field given several times matches any
+/// of its values.
+#[derive(Debug, Default)]
+pub struct Filters(Vec<(String, Vec<String>)>);
+
+impl Filters {
+    pub fn from_request(req: &Request) -> Self {
+        let mut filters: Vec<_> = req
+            .queries()
+            .iter_all()
+            .filter(|(field, _)| {
+                !field.starts_with('_')
+                    && !PAGE_PARAMS.contains(&field.as_str())
+                    && field.as_str() != ACCESS_TOKEN
+            })
+            .map(|(field, values)| (field.clone(), values.clone()))
+            .collect();
+        filters.sort();
+        Self(filters)
+    }
+
+    /// Drops the items that do not have the values asked for.
+    pub fn retain(&self, items: &mut Vec<serde_json::Value>) {
+        if self.0.is_empty() {
+            return;
+        }
+        items.retain(|item| {
+            self.0.iter().all(|(field, values)| {
+                values
+                    .iter()
+                    .any(|value| equals(&item[field.as_str()], value))
+            })
+        });
+    }
+}
+
+/// Whether `field` holds `value`, as written in a query string.
+fn equals(field: &serde_json::Value, value: &str) -> bool {
+    match field {
+        serde_json::Value::String(text) => text == value,
+        serde_json::Value::Number(number) => number.to_string() == value,
+        serde_json::Value::Bool(boolean) => value.parse() == Ok(*boolean),
+        serde_json::Value::Null => value == "null",
+        serde_json::Value::Array(_) | serde_json::Value::Object(_) => false,
+    }
+}
@@ -1,9 +1,14 @@
-use crate::error::AppResult;
-use crate::utils::{
-    add_item_to_json_file, convert_string_to_json, create_empty_json_file, delete_from_json_file,
-    get_item_by_id, read_json_from_file, update_json_file,
-};
+use crate::conditional::{check_if_match, etag, not_modified};
+use crate::error::{AppError, AppResult};
+use crate::filters::Filters;
+use crate::history::Revision;
+use crate::jwt::{current_user, Claims};
+use crate::ops;
+use crate::pagination::{Pagination, ResponseShape};
+use crate::relations::{children_of, restore_with_references, Relations};
+use crate::trash::{is_deleted, purge, with_deleted};
 use crate::AppConfig;
+use salvo::http::header::ETAG;
 use salvo::http::StatusCode;
 use salvo::prelude::*;
 
@@ -13,67 +18,299 @@ use serde::Serialize;
 struct ApiResponse {
     data: Vec<serde_json::Value>,
     total: usize,
-    limit: usize,
+    limit: Option<usize>,
     skip: usize,
+    next_cursor: Option<String>,
+    prev_cursor: Option<String>,
 }
 
 #[handler]
-pub async fn get_all(req: &mut Request, depot: &mut Depot) -> AppResult<Json<ApiResponse>> {
+pub async fn get_all(req: &mut Request, res: &mut Response, depot: &mut Depot) -> AppResult<()> {
     let app_config = depot.obtain::<AppConfig>().unwrap().clone();
-    let data_dir = &app_config.data_dir;
     let file_path = req.param::<String>("f").unwrap();
-    let limit = req.query::<usize>("limit").unwrap_or(30);
-    let skip = req.query::<usize>("skip").unwrap_or(0);
 
-    let json_string = match read_json_from_file(data_dir, &file_path).await {
-        Ok(s) => s,
-        Err(_) => {
-            create_empty_json_file(data_dir, &file_path).await?;
-            String::from("[]")
+    let user = current_user(depot);
+
+    let mut items = app_config.storage.get_all(&file_path).await?;
+    retain_visible(req, &app_config, &file_path, user.as_ref(), &mut items);
+    render_list(req, res, &app_config, &file_path, user.as_ref(), items).await
+}
+
+#[handler]
+pub async fn get_trash(req: &mut Request, res: &mut Response, depot: &mut Depot) -> AppResult<()> {
+    let app_config = depot.obtain::<AppConfig>().unwrap().clone();
+    let file_path = req.param::<String>("f").unwrap();
+
+    let user = current_user(depot);
+
+    let mut items = app_config.storage.get_all(&file_path).await?;
+    items.retain(|item| {
+        is_deleted(item) && !ops::foreign(&app_config, &file_path, item, user.as_ref())
+    });
+    render_list(req, res, &app_config, &file_path, user.as_ref(), items).await
+}
+
+#[handler]
+pub async fn get_children(
+    req: &mut Request,
+    res: &mut Response,
+    depot: &mut Depot,
+) -> AppResult<()> {
+    let app_config = depot.obtain::<AppConfig>().unwrap().clone();
+    let file_path = req.param::<String>("f").unwrap();
+    let id = req.param::<u64>("id").unwrap();
+    let child = req.param::<String>("child").unwrap();
+    let user = current_user(depot);
+
+    let visible = match app_config.storage.get(&file_path, id).await {
+        Ok(item) => {
+            let method = req.method();
+            ops::check_owner(&app_config, &file_path, method, Some(&item), user.as_ref())?;
+            !ops::hidden(&app_config, &item, with_deleted(req))
         }
+        Err(_) => false,
     };
+    if !visible {
+        res.status_code(StatusCode::NOT_FOUND);
+        res.render(Json(serde_json::json!({})));
+        return Ok(());
+    }
 
-    let json_value = convert_string_to_json(&json_string)?;
-    let total_records = json_value.as_array().unwrap().len();
+    let mut items = children_of(
+        app_config.storage.as_ref(),
+        &app_config.relationships,
+        &file_path,
+        id,
+        &child,
+    )
+    .await?;
+    retain_visible(req, &app_config, &child, user.as_ref(), &mut items);
+    render_list(req, res, &app_config, &child, user.as_ref(), items).await
+}
 
-    let limited_json_value: Vec<&serde_json::Value> = json_value
-        .as_array()
-        .unwrap()
-        .iter()
-        .skip(skip)
-        .take(limit)
-        .collect();
+#[handler]
+pub async fn get_changelog(
+    req: &mut Request,
+    res: &mut Response,
+    depot: &mut Depot,
+) -> AppResult<()> {
+    let app_config = depot.obtain::<AppConfig>().unwrap().clone();
+    let file_path = req.param::<String>("f").unwrap();
 
-    let api_response = ApiResponse {
-        data: limited_json_value.into_iter().cloned().collect(),
-        total: total_records,
-        limit,
-        skip,
+    let Some(history) = &app_config.history else {
+        res.status_code(StatusCode::NOT_FOUND);
+        res.render(Json(serde_json::json!({})));
+        return Ok(());
     };
+    let user = current_user(depot);
+    let mut entries = history.changelog(&file_path).await?;
+    retain_own_changes(&app_config, &file_path, user.as_ref(), &mut entries);
+    render_list(req, res, &app_config, &file_path, user.as_ref(), entries).await
+}
 
-    Ok(Json(api_response))
+#[handler]
+pub async fn get_history(
+    req: &mut Request,
+    res: &mut Response,
+    depot: &mut Depot,
+) -> AppResult<()> {
+    let app_config = depot.obtain::<AppConfig>().unwrap().clone();
+    let file_path = req.param::<String>("f").unwrap();
+    let id = req.param::<u64>("id").unwrap();
+
+    let Some(history) = &app_config.history else {
+        res.status_code(StatusCode::NOT_FOUND);
+        res.render(Json(serde_json::json!({})));
+        return Ok(());
+    };
+    let user = current_user(depot);
+    let mut entries = history.item_history(&file_path, id).await?;
+    retain_own_changes(&app_config, &file_path, user.as_ref(), &mut entries);
+    render_list(req, res, &app_config, &file_path, user.as_ref(), entries).await
 }
 
 #[handler]
-pub async fn get_one(
+pub async fn rollback(
     req: &mut Request,
     res: &mut Response,
     depot: &mut Depot,
 ) -> AppResult<Json<serde_json::Value>> {
     let app_config = depot.obtain::<AppConfig>().unwrap().clone();
-    let data_dir = &app_config.data_dir;
+    let file_path = req.param::<String>("f").unwrap();
+
+    let Some(history) = &app_config.history else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return Ok(Json(serde_json::json!({})));
+    };
+    let to = req
+        .query::<String>("to")
+        .ok_or_else(|| AppError::BadRequest("missing `to` revision".to_string()))?;
+    let to = Revision::parse(&to)?;
+    if ops::owner_field(&app_config, &file_path).is_some() {
+        return Err(AppError::Forbidden(format!(
+            "`{file_path}` is shared by several users and cannot be rolled back over the API"
+        )));
+    }
+
+    let changed = history
+        .rollback(app_config.storage.as_ref(), &file_path, &to)
+        .await?;
+    Ok(Json(serde_json::json!({ "to": to.to_string(), "changed": changed })))
+}
+
+/// Drops the items of `f` owned by someone other than `user`, and
+/// soft-deleted items unless they were asked for with `_withDeleted`.
+fn retain_visible(
+    req: &Request,
+    app_config: &AppConfig,
+    f: &str,
+    user: Option<&Claims>,
+    items: &mut Vec<serde_json::Value>,
+) {
+    let with_deleted = with_deleted(req);
+    items.retain(|item| {
+        !ops::hidden(app_config, item, with_deleted) && !ops::foreign(app_config, f, item, user)
+    });
+}
+
+/// Drops the history entries of items of `f` owned by someone other than
+/// `user`, and redacts the items of the others.
+fn retain_own_changes(
+    app_config: &AppConfig,
+    f: &str,
+    user: Option<&Claims>,
+    entries: &mut Vec<serde_json::Value>,
+) {
+    entries.retain(|entry| {
+        let item = if entry["after"].is_null() {
+            &entry["before"]
+        } else {
+            &entry["after"]
+        };
+        !ops::foreign(app_config, f, item, user)
+    });
+    for entry in entries {
+        ops::redact(app_config, f, &mut entry["before"]);
+        ops::redact(app_config, f, &mut entry["after"]);
+    }
+}
+
+/// Embeds and expands the relations asked for in `items` of `f`, leaving
+/// out the related items `user` may not see, as `retain_visible` does, and
+/// redacting the others.
+async fn apply_relations(
+    req: &Request,
+    app_config: &AppConfig,
+    f: &str,
+    user: Option<&Claims>,
+    items: &mut [serde_json::Value],
+) -> AppResult<()> {
+    Relations::from_request(req)
+        .apply(
+            app_config.storage.as_ref(),
+            &app_config.relationships,
+            f,
+            items,
+            |related, related_items| {
+                retain_visible(req, app_config, related, user, related_items);
+                for item in related_items {
+                    ops::redact(app_config, related, item);
+                }
+            },
+        )
+        .await
+}
+
+/// Filters and paginates `items` of collection `f`, and renders them in the
+/// response shape asked for.
+async fn render_list(
+    req: &Request,
+    res: &mut Response,
+    app_config: &AppConfig,
+    f: &str,
+    user: Option<&Claims>,
+    mut items: Vec<serde_json::Value>,
+) -> AppResult<()> {
+    let mut pagination = Pagination::from_request(req, app_config.page_sizes)?;
+    let shape = ResponseShape::from_request(req, app_config.response_shape)?;
+
+    // Redacted fields cannot be filtered on.
+    for item in &mut items {
+        ops::redact(app_config, f, item);
+    }
+    Filters::from_request(req).retain(&mut items);
+
+    let total_records = items.len();
+    let mut data = pagination.apply(items);
+    apply_relations(req, app_config, f, user, &mut data).await?;
+
+    if shape == ResponseShape::Array {
+        res.add_header("x-total-count", total_records, true)?;
+        res.add_header("link", pagination.link_header(req, total_records), true)?;
+        if !not_modified(req, res, &etag(&data)?)? {
+            res.render(Json(data));
+        }
+        return Ok(());
+    }
+
+    let api_response = ApiResponse {
+        next_cursor: pagination.next_cursor(total_records, &data),
+        prev_cursor: pagination.prev_cursor(&data),
+        data,
+        total: total_records,
+        limit: pagination.limit,
+        skip: pagination.skip,
+    };
+
+    if !not_modified(req, res, &etag(&api_response)?)? {
+        res.render(Json(api_response));
+    }
+    Ok(())
+}
+
+#[handler]
+pub async fn get_one(req: &mut Request, res: &mut Response, depot: &mut Depot) -> AppResult<()> {
+    let app_config = depot.obtain::<AppConfig>().unwrap().clone();
     let file_path = req.param::<String>("f").unwrap();
     let id = req.param::<u64>("id").unwrap();
+    let user = current_user(depot);
 
-    let result = get_item_by_id(data_dir, &file_path, id).await;
+    let result = app_config.storage.get(&file_path, id).await;
+    if let Ok(json_value) = &result {
+        ops::check_owner(
+            &app_config,
+            &file_path,
+            req.method(),
+            Some(json_value),
+            user.as_ref(),
+        )?;
+    }
 
     match result {
-        Ok(json_value) => Ok(Json(json_value)),
+        Ok(json_value) if ops::hidden(&app_config, &json_value, with_deleted(req)) => {
+            res.status_code(StatusCode::NOT_FOUND);
+            res.render(Json(serde_json::json!({})));
+        }
+        Ok(mut json_value) => {
+            ops::redact(&app_config, &file_path, &mut json_value);
+            apply_relations(
+                req,
+                &app_config,
+                &file_path,
+                user.as_ref(),
+                std::slice::from_mut(&mut json_value),
+            )
+            .await?;
+            if !not_modified(req, res, &etag(&json_value)?)? {
+                res.render(Json(json_value));
+            }
+        }
         Err(_) => {
             res.status_code(StatusCode::NOT_FOUND);
-            Ok(Json(serde_json::json!({})))
+            res.render(Json(serde_json::json!({})));
         }
     }
+    Ok(())
 }
 
 #[handler]
@@ -83,14 +320,15 @@ pub async fn add_one(
     depot: &mut Depot,
 ) -> AppResult<Json<serde_json::Value>> {
     let app_config = depot.obtain::<AppConfig>().unwrap().clone();
-    let data_dir = &app_config.data_dir;
     let file_path = req.param::<String>("f").unwrap();
 
     let new_item_json = req.parse_body::<serde_json::Value>().await?;
 
-    let result = add_item_to_json_file(data_dir, &file_path, new_item_json).await?;
+    let user = current_user(depot);
+
+    let result = ops::create(&app_config, &file_path, new_item_json, user.as_ref()).await?;
     res.status_code(StatusCode::CREATED);
-    Ok(Json(result))
+    Ok(Json(ops::redacted(&app_config, &file_path, &result)))
 }
 
 #[handler]
@@ -100,19 +338,92 @@ pub async fn update_one(
     depot: &mut Depot,
 ) -> AppResult<Json<serde_json::Value>> {
     let app_config = depot.obtain::<AppConfig>().unwrap().clone();
-    let data_dir = &app_config.data_dir;
     let file_path = req.param::<String>("f").unwrap();
     let id = req.param::<u64>("id").unwrap();
 
     let updated_item_json = req.parse_body::<serde_json::Value>().await?;
+    let user = current_user(depot);
+    let previous = ops::current(&app_config, &file_path, id).await;
+    ops::check_owner(
+        &app_config,
+        &file_path,
+        req.method(),
+        previous.as_ref(),
+        user.as_ref(),
+    )?;
+    let seen = previous
+        .as_ref()
+        .map(|item| ops::redacted(&app_config, &file_path, item));
+    let conditional = check_if_match(req, seen.as_ref(), app_config.require_if_match)?;
 
-    let found_item = update_json_file(data_dir, &file_path, id, &updated_item_json).await?;
+    match ops::replace(
+        &app_config,
+        &file_path,
+        id,
+        updated_item_json,
+        previous,
+        user.as_ref(),
+        conditional,
+    )
+    .await?
+    {
+        Some(mut updated_item) => {
+            ops::redact(&app_config, &file_path, &mut updated_item);
+            res.add_header(ETAG, etag(&updated_item)?.to_string(), true)?;
+            Ok(Json(updated_item))
+        }
+        None => {
+            res.status_code(StatusCode::NOT_FOUND);
+            Ok(Json(serde_json::json!({})))
+        }
+    }
+}
 
-    if found_item {
-        Ok(Json(updated_item_json))
-    } else {
-        res.status_code(StatusCode::NOT_FOUND);
-        Ok(Json(serde_json::json!({})))
+#[handler]
+pub async fn patch_one(
+    req: &mut Request,
+    res: &mut Response,
+    depot: &mut Depot,
+) -> AppResult<Json<serde_json::Value>> {
+    let app_config = depot.obtain::<AppConfig>().unwrap().clone();
+    let file_path = req.param::<String>("f").unwrap();
+    let id = req.param::<u64>("id").unwrap();
+
+    let patch_json = req.parse_body::<serde_json::Value>().await?;
+    let user = current_user(depot);
+    let current = ops::current(&app_config, &file_path, id).await;
+    ops::check_owner(
+        &app_config,
+        &file_path,
+        req.method(),
+        current.as_ref(),
+        user.as_ref(),
+    )?;
+    let seen = current
+        .as_ref()
+        .map(|item| ops::redacted(&app_config, &file_path, item));
+    let conditional = check_if_match(req, seen.as_ref(), app_config.require_if_match)?;
+
+    match ops::patch(
+        &app_config,
+        &file_path,
+        id,
+        patch_json,
+        current,
+        user.as_ref(),
+        conditional,
+    )
+    .await?
+    {
+        Some(mut patched_item) => {
+            ops::redact(&app_config, &file_path, &mut patched_item);
+            res.add_header(ETAG, etag(&patched_item)?.to_string(), true)?;
+            Ok(Json(patched_item))
+        }
+        None => {
+            res.status_code(StatusCode::NOT_FOUND);
+            Ok(Json(serde_json::json!({})))
+        }
     }
 }
 
@@ -123,12 +434,32 @@ pub async fn delete_one(
     depot: &mut Depot,
 ) -> AppResult<Json<serde_json::Value>> {
     let app_config = depot.obtain::<AppConfig>().unwrap().clone();
-    let data_dir = &app_config.data_dir;
 
     let file_path = req.param::<String>("f").unwrap();
     let id = req.param::<u64>("id").unwrap();
 
-    let found_item = delete_from_json_file(data_dir, &file_path, id).await?;
+    let current = app_config.storage.get(&file_path, id).await.ok();
+    ops::check_owner(
+        &app_config,
+        &file_path,
+        req.method(),
+        current.as_ref(),
+        current_user(depot).as_ref(),
+    )?;
+    let seen = current
+        .as_ref()
+        .map(|item| ops::redacted(&app_config, &file_path, item));
+    let conditional = check_if_match(req, seen.as_ref(), app_config.require_if_match)?;
+
+    let found_item = ops::delete(
+        &app_config,
+        &file_path,
+        id,
+        current.as_ref(),
+        purge(req),
+        conditional,
+    )
+    .await?;
     if found_item {
         res.status_code(StatusCode::NO_CONTENT);
     } else {
@@ -137,3 +468,39 @@ pub async fn delete_one(
 
     Ok(Json(serde_json::json!({})))
 }
+
+#[handler]
+pub async fn restore_one(
+    req: &mut Request,
+    res: &mut Response,
+    depot: &mut Depot,
+) -> AppResult<Json<serde_json::Value>> {
+    let app_config = depot.obtain::<AppConfig>().unwrap().clone();
+    let file_path = req.param::<String>("f").unwrap();
+    let id = req.param::<u64>("id").unwrap();
+
+    let current = app_config.storage.get(&file_path, id).await.ok();
+    ops::check_owner(
+        &app_config,
+        &file_path,
+        req.method(),
+        current.as_ref(),
+        current_user(depot).as_ref(),
+    )?;
+
+    let restored = restore_with_references(
+        app_config.storage.as_ref(),
+        &app_config.relationships,
+        &file_path,
+        id,
+    )
+    .await?;
+
+    match restored {
+        Some(item) => Ok(Json(ops::redacted(&app_config, &file_path, &item))),
+        None => {
+            res.status_code(StatusCode::NOT_FOUND);
+            Ok(Json(serde_json::json!({})))
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use salvo::prelude::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::sync::Mutex;
+
+use crate::changes::{Change, Operation};
+use crate::error::{AppError, AppResult};
+use crate::storage::{item_id, Storage};
+use crate::timestamps::TimestampFormat;
+use crate::AppConfig;
+
+/// Directory of the data directory holding one `{f}.jsonl` changelog per
+/// collection.
+pub const HISTORY_DIR: &str = ".history";
+
+tokio::task_local! {
+    /// Address of the client whose request is being handled.
+    static CLIENT: Option<String>;
+}
+
+/// Address of the client making `req`. Behind a trusted proxy, this is the
+/// address the proxy added last to `X-Forwarded-For`, the earlier ones being
+/// whatever the client sent.
+pub fn client_address(req: &Request, trust_proxy: bool) -> Option<String> {
+    trust_proxy
+        .then(|| req.header::<String>("x-forwarded-for"))
+        .flatten()
+        .and_then(|forwarded| {
+            forwarded
+                .rsplit(',')
+                .map(str::trim)
+                .find(|ip| !ip.is_empty())
+                .map(str::to_string)
+        })
+        .or_else(|| req.remote_addr().ip().map(|ip| ip.to_string()))
+}
+
+/// Remembers the client address of each request, so that the changes it
+/// makes can be attributed to it.
+#[handler]
+pub async fn track_client(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+    ctrl: &mut FlowCtrl,
+) {
+    let trust_proxy = depot.obtain::<AppConfig>().unwrap().trust_proxy;
+    let client = client_address(req, trust_proxy);
+    CLIENT.scope(client, ctrl.call_next(req, depot, res)).await;
+}
+
+/// A point of a collection's history to roll back to: just after a given
+/// revision, or as it was at a given time.
+#[derive(Clone, Copy, Debug)]
+pub enum Revision {
+    Number(u64),
+    Time(OffsetDateTime),
+}
+
+impl Revision {
+    /// Reads a revision number or an RFC 3339 timestamp.
+    pub fn parse(value: &str) -> AppResult<Self> {
+        if let Ok(number) = value.parse() {
+            return Ok(Self::Number(number));
+        }
+        OffsetDateTime::parse(value, &Rfc3339)
+            .map(Self::Time)
+            .map_err(|_| {
+                AppError::BadRequest(format!(
+                    "invalid revision `{value}`: expected a revision number or an RFC 3339 timestamp"
+                ))
+            })
+    }
+
+    /// Whether history `entry` was recorded after this point.
+    fn precedes(&self, entry: &serde_json::Value) -> bool {
+        match self {
+            Revision::Number(number) => item_id(entry).is_some_and(|id| id > *number),
+            Revision::Time(time) => entry["timestamp"]
+                .as_str()
+                .and_then(|timestamp| OffsetDateTime::parse(timestamp, &Rfc3339).ok())
+                .is_some_and(|timestamp| timestamp > *time),
+        }
+    }
+}
+
+impl fmt::Display for Revision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Revision::Number(number) => write!(f, "revision {number}"),
+            Revision::Time(time) => write!(f, "{}", time.format(&Rfc3339).unwrap_or_default()),
+        }
+    }
+}
+
+/// Append-only log of the changes made to every item, one collection of
+/// entries per collection of data. Entries are numbered from 1 per
+/// collection; their `id` is that revision number.
+#[derive(Debug)]
+pub struct History {
+    log: Arc<dyn Storage>,
+    revisions: Mutex<HashMap<String, u64>>,
+}
+
+impl History {
+    pub fn new(log: Arc<dyn Storage>) -> Self {
+        Self {
+            log,
+            revisions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Appends `change` to the changelog of its collection. Collection-wide
+    /// resets are not item changes and are left out.
+    pub async fn record(&self, change: &Change) -> AppResult<()> {
+        if change.operation == Operation::Reset {
+            return Ok(());
+        }
+        let f = change.collection.as_str();
+
+        let mut revisions = self.revisions.lock().await;
+        let revision = match revisions.get(f) {
+            Some(revision) => revision + 1,
+            None => self.last_revision(f).await? + 1,
+        };
+
+        let entry = serde_json::json!({
+            "id": revision,
+            "timestamp": TimestampFormat::Rfc3339.now(),
+            "operation": change.operation.as_str(),
+            "itemId": change.id,
+            "before": change.before,
+            "after": change.after,
+            "client": CLIENT.try_with(Clone::clone).ok().flatten(),
+        });
+        self.log.insert(f, entry).await?;
+        revisions.insert(f.to_string(), revision);
+        Ok(())
+    }
+
+    async fn last_revision(&self, f: &str) -> AppResult<u64> {
+        Ok(self
+            .changelog(f)
+            .await?
+            .iter()
+            .filter_map(item_id)
+            .max()
+            .unwrap_or(0))
+    }
+
+    /// Every change made to collection `f`, oldest first.
+    pub async fn changelog(&self, f: &str) -> AppResult<Vec<serde_json::Value>> {
+        if self.log.list_collections().await?.iter().any(|c| c == f) {
+            self.log.get_all(f).await
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    /// Puts every item of `f` changed after `to` back in the state it had
+    /// then, writing through `storage` so that the rollback is itself
+    /// recorded (and can be rolled back). Returns how many items changed.
+    pub async fn rollback(
+        &self,
+        storage: &dyn Storage,
+        f: &str,
+        to: &Revision,
+    ) -> AppResult<usize> {
+        // The state each item had at `to` is the `before` of the first change
+        // made to it afterwards.
+        let mut restores: Vec<(u64, serde_json::Value)> = Vec::new();
+        for entry in self.changelog(f).await? {
+            let Some(id) = entry["itemId"].as_u64() else {
+                continue;
+            };
+            if to.precedes(&entry) && restores.iter().all(|(restored, _)| *restored != id) {
+                restores.push((id, entry["before"].clone()));
+            }
+        }
+
+        let mut changed = 0;
+        for (id, before) in restores {
+            let exists = storage.get(f, id).await.is_ok();
+            match (before.is_null(), exists) {
+                (true, true) => {
+                    storage.delete(f, id).await?;
+                }
+                (true, false) => continue,
+                (false, true) => {
+                    storage.replace(f, id, &before).await?;
+                }
+                (false, false) => {
+                    storage.insert(f, before).await?;
+                }
+            }
+            changed += 1;
+        }
+        Ok(changed)
+    }
+
+    /// Every change made to item `id` of collection `f`, oldest first.
+    pub async fn item_history(&self, f: &str, id: u64) -> AppResult<Vec<serde_json::Value>> {
+        let mut entries = self.changelog(f).await?;
+        entries.retain(|entry| entry["itemId"].as_u64() == Some(id));
+        Ok(entries)
+    }
+}
@@ -1,51 +1,44 @@
+use crate::auth::caller;
 use crate::error::AppResult;
-use crate::utils::delete_collection_sync;
-use crate::AppConfig;
+use crate::{ops, AppConfig};
+use salvo::http::Method;
 use salvo::prelude::*;
-use std::path::PathBuf;
 
 #[handler]
 pub async fn index(res: &mut Response, depot: &mut Depot) -> AppResult<()> {
     let app_config = depot.obtain::<AppConfig>().unwrap().clone();
-    let data_dir = &app_config.data_dir;
-
-    let mut data_content = tokio::fs::read_dir(data_dir).await?;
-    let mut data_files: Vec<PathBuf> = Vec::new();
-
-    while let Some(data_input) = data_content.next_entry().await? {
-        let ruta = data_input.path();
-        let name = data_input.file_name();
-        if ruta.is_file() {
-            data_files.push(name.into());
-        }
-    }
-
-    let data_files: Vec<String> = data_files
-        .into_iter()
-        .filter_map(|path| {
-            path.file_stem().map(|stem| {
-                let stem_str = stem.to_string_lossy();
-                format!(
-                    r#"
-                    <div class="card mb-3">
-                      <div class="card-content">
-                        <div class="columns is-vcentered">
-                          <div class="column is-6">
-                            <span class="is-family-code has-text-link">/api/{stem}</span>
-                          </div>
-                          <div class="column is-6 has-text-right">
-                            <a href="/api/{stem}" target="_blank" class="button is-info is-light">Open</a>
-                            <button class="button is-danger is-light js-delete-btn" data-name="{stem}">
-                              Delete
-                            </button>
-                          </div>
-                        </div>
+    let collections = app_config.storage.list_collections().await?;
+
+    let storage_location = match app_config.storage.location() {
+        Some(data_dir) => format!("<p>JSON files are stored in <strong>{data_dir}</strong></p>"),
+        None => String::from(
+            "<p>Collections are kept <strong>in memory</strong> and never written to disk</p>",
+        ),
+    };
+
+    let data_files: Vec<String> = collections
+        .iter()
+        .map(|stem_str| {
+            format!(
+                r#"
+                <div class="card mb-3">
+                  <div class="card-content">
+                    <div class="columns is-vcentered">
+                      <div class="column is-6">
+                        <span class="is-family-code has-text-link">/api/{stem}</span>
+                      </div>
+                      <div class="column is-6 has-text-right">
+                        <a href="/api/{stem}" target="_blank" class="button is-info is-light">Open</a>
+                        <button class="button is-danger is-light js-delete-btn" data-name="{stem}">
+                          Delete
+                        </button>
                       </div>
                     </div>
-                    "#,
-                    stem = stem_str
-                )
-            })
+                  </div>
+                </div>
+                "#,
+                stem = stem_str
+            )
         })
         .collect();
 
@@ -108,7 +101,7 @@ let html = format!(
 
                     {collections}
 
-                    <p>JSON files are stored in <strong>{data_dir}</strong></p>
+                    {storage_location}
 
                     <h3 class="title is-4 has-text-grey-dark mt-4">Examples</h3>
 
@@ -224,13 +217,19 @@ let html = format!(
     Ok(())
 }
 
+/// Deletes a collection, for callers the access rules let delete from it.
 #[handler]
-pub async fn delete_collection(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+pub async fn delete_collection(
+    req: &mut Request,
+    res: &mut Response,
+    depot: &mut Depot,
+) -> AppResult<()> {
     let app_config = depot.obtain::<AppConfig>().unwrap().clone();
-    let data_dir = &app_config.data_dir;
     let file_path = req.param::<String>("f").unwrap();
+    ops::check_access(&app_config, &file_path, &Method::DELETE, &caller(depot))?;
 
-    delete_collection_sync(data_dir, &file_path).await.unwrap();
+    app_config.storage.delete_collection(&file_path).await?;
 
     res.render(Redirect::other("/"));
+    Ok(())
 }
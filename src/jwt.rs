@@ -0,0 +1,362 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use salvo::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use time::OffsetDateTime;
+
+use crate::auth::caller;
+use crate::error::{AppError, AppResult};
+use crate::ops;
+use crate::AppConfig;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Encoded header of every token issued: HMAC-SHA256 signed JWTs.
+const HEADER: &str = r#"{"alg":"HS256","typ":"JWT"}"#;
+
+/// Field of user items holding the password hash.
+const PASSWORD: &str = "password";
+
+/// Settings of the login flow: users register and log in against the
+/// `users` collection and get JSON Web Tokens signed with `secret`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JwtConfig {
+    pub secret: String,
+    /// Lifetime of issued tokens, in seconds.
+    #[serde(default = "default_expires_in")]
+    pub expires_in: i64,
+    #[serde(default = "default_users")]
+    pub users: String,
+    /// Collections whose items belong to the user who created them, with
+    /// the field holding the id of that user.
+    #[serde(default)]
+    pub owners: HashMap<String, String>,
+}
+
+fn default_expires_in() -> i64 {
+    3600
+}
+
+fn default_users() -> String {
+    "users".to_string()
+}
+
+impl JwtConfig {
+    pub fn new(secret: String) -> Self {
+        Self {
+            secret,
+            expires_in: default_expires_in(),
+            users: default_users(),
+            owners: HashMap::new(),
+        }
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(self.secret.as_bytes()).expect("HMAC takes keys of any size")
+    }
+
+    /// A signed token for `user`, valid for `expires_in` seconds.
+    fn issue(&self, user: &serde_json::Value) -> AppResult<String> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let claims = Claims {
+            sub: user["id"]
+                .as_str()
+                .map_or_else(|| user["id"].to_string(), str::to_string),
+            email: user["email"].as_str().unwrap_or_default().to_string(),
+            iat: now,
+            exp: now + self.expires_in,
+        };
+
+        let signed = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(HEADER),
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims)?)
+        );
+        let mut mac = self.mac();
+        mac.update(signed.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        Ok(format!("{signed}.{signature}"))
+    }
+
+    /// The claims of `token`, once its signature and expiry are checked.
+    pub fn verify(&self, token: &str) -> AppResult<Claims> {
+        let invalid = || AppError::InvalidToken("malformed access token".to_string());
+        let (signed, signature) = token.rsplit_once('.').ok_or_else(invalid)?;
+        let (header, payload) = signed.split_once('.').ok_or_else(invalid)?;
+
+        let header: serde_json::Value =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header).map_err(|_| invalid())?)
+                .map_err(|_| invalid())?;
+        if header["alg"] != "HS256" {
+            return Err(AppError::InvalidToken(
+                "unsupported token algorithm".to_string(),
+            ));
+        }
+
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| invalid())?;
+        let mut mac = self.mac();
+        mac.update(signed.as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| AppError::InvalidToken("bad token signature".to_string()))?;
+
+        let claims: Claims =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).map_err(|_| invalid())?)
+                .map_err(|_| invalid())?;
+        if claims.exp <= OffsetDateTime::now_utc().unix_timestamp() {
+            return Err(AppError::InvalidToken(
+                "the access token expired".to_string(),
+            ));
+        }
+        Ok(claims)
+    }
+
+    /// `{accessToken, user}` for a user who just registered or logged in.
+    fn session(&self, mut user: serde_json::Value) -> AppResult<serde_json::Value> {
+        let access_token = self.issue(&user)?;
+        strip_password(&mut user);
+        Ok(serde_json::json!({ "accessToken": access_token, "user": user }))
+    }
+}
+
+/// The signed-in user a request acts for, as carried by its token.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Claims {
+    /// Id of the user.
+    pub sub: String,
+    pub email: String,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+impl Claims {
+    /// The id of the user as stored in the owner field of items.
+    pub fn owner(&self) -> serde_json::Value {
+        serde_json::from_str(&self.sub).unwrap_or_else(|_| self.sub.clone().into())
+    }
+}
+
+/// Removes the password hash of `user`, which no client may read.
+pub fn strip_password(user: &mut serde_json::Value) {
+    if let Some(fields) = user.as_object_mut() {
+        fields.remove(PASSWORD);
+    }
+}
+
+/// The user signed in for the request, if any.
+pub fn current_user(depot: &Depot) -> Option<Claims> {
+    caller(depot).user().cloned()
+}
+
+#[derive(Deserialize)]
+struct Credentials {
+    email: String,
+    password: String,
+}
+
+impl Credentials {
+    fn from_body(body: &serde_json::Value) -> AppResult<Self> {
+        serde_json::from_value(body.clone()).map_err(|_| {
+            AppError::BadRequest("`email` and `password` strings are required".to_string())
+        })
+    }
+}
+
+/// Salted SHA-256 of `password`, as `sha256$<salt>$<digest>`.
+fn hash_password(password: &str) -> String {
+    let salt: [u8; 16] = rand::random();
+    format!(
+        "sha256${}${}",
+        URL_SAFE_NO_PAD.encode(salt),
+        URL_SAFE_NO_PAD.encode(digest(&salt, password))
+    )
+}
+
+fn verify_password(hash: &str, password: &str) -> bool {
+    let mut parts = hash.split('$');
+    let (Some("sha256"), Some(salt), Some(expected), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return false;
+    };
+    URL_SAFE_NO_PAD
+        .decode(salt)
+        .is_ok_and(|salt| URL_SAFE_NO_PAD.encode(digest(&salt, password)) == expected)
+}
+
+fn digest(salt: &[u8], password: &str) -> Vec<u8> {
+    Sha256::new()
+        .chain_update(salt)
+        .chain_update(password)
+        .finalize()
+        .to_vec()
+}
+
+/// The user registered with `email`, unless soft-deleted.
+async fn find_user(
+    app_config: &AppConfig,
+    jwt: &JwtConfig,
+    email: &str,
+) -> AppResult<Option<serde_json::Value>> {
+    let users = app_config.storage.get_all(&jwt.users).await?;
+    Ok(users
+        .into_iter()
+        .find(|user| user["email"] == email && !ops::hidden(app_config, user, false)))
+}
+
+/// Adds the user of the body, which must have an `email` and a `password`,
+/// to the users collection and signs them in. The id of the user, which
+/// their tokens carry, is always picked by the storage.
+#[handler]
+pub async fn register(
+    req: &mut Request,
+    res: &mut Response,
+    depot: &mut Depot,
+) -> AppResult<Json<serde_json::Value>> {
+    let app_config = depot.obtain::<AppConfig>().unwrap().clone();
+    let Some(jwt) = &app_config.auth.jwt else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return Ok(Json(serde_json::json!({})));
+    };
+
+    let mut user = req.parse_body::<serde_json::Value>().await?;
+    let credentials = Credentials::from_body(&user)?;
+    if find_user(&app_config, jwt, &credentials.email)
+        .await?
+        .is_some()
+    {
+        return Err(AppError::Conflict(format!(
+            "`{}` is already registered",
+            credentials.email
+        )));
+    }
+
+    if let Some(fields) = user.as_object_mut() {
+        fields.remove("id");
+    }
+    user[PASSWORD] = hash_password(&credentials.password).into();
+    let user = ops::create(&app_config, &jwt.users, user, None).await?;
+    res.status_code(StatusCode::CREATED);
+    Ok(Json(jwt.session(user)?))
+}
+
+#[handler]
+pub async fn login(
+    req: &mut Request,
+    res: &mut Response,
+    depot: &mut Depot,
+) -> AppResult<Json<serde_json::Value>> {
+    let app_config = depot.obtain::<AppConfig>().unwrap().clone();
+    let Some(jwt) = &app_config.auth.jwt else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return Ok(Json(serde_json::json!({})));
+    };
+
+    let credentials = Credentials::from_body(&req.parse_body::<serde_json::Value>().await?)?;
+    let user = find_user(&app_config, jwt, &credentials.email)
+        .await?
+        .filter(|user| {
+            user[PASSWORD]
+                .as_str()
+                .is_some_and(|hash| verify_password(hash, &credentials.password))
+        })
+        .ok_or_else(|| AppError::Unauthorized("wrong email or password".to_string()))?;
+    Ok(Json(jwt.session(user)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> JwtConfig {
+        JwtConfig::new("secret".to_string())
+    }
+
+    fn user() -> serde_json::Value {
+        serde_json::json!({ "id": 7, "email": "ann@example.com" })
+    }
+
+    /// A token signed with `jwt` whose header and payload are `header` and
+    /// `claims`.
+    fn sign(jwt: &JwtConfig, header: &str, claims: &serde_json::Value) -> String {
+        let signed = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let mut mac = jwt.mac();
+        mac.update(signed.as_bytes());
+        format!(
+            "{signed}.{}",
+            URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+        )
+    }
+
+    #[test]
+    fn verify_reads_back_issued_tokens() {
+        let jwt = config();
+        let claims = jwt.verify(&jwt.issue(&user()).unwrap()).unwrap();
+        assert_eq!(claims.sub, "7");
+        assert_eq!(claims.email, "ann@example.com");
+        assert_eq!(claims.exp - claims.iat, jwt.expires_in);
+        assert_eq!(claims.owner(), serde_json::json!(7));
+    }
+
+    #[test]
+    fn verify_rejects_bad_signatures() {
+        let token = config().issue(&user()).unwrap();
+        let other = JwtConfig::new("other secret".to_string());
+        assert!(other.verify(&token).is_err());
+
+        let (signed, _) = token.rsplit_once('.').unwrap();
+        let (header, _) = signed.split_once('.').unwrap();
+        let forged = serde_json::json!({ "sub": "1", "email": "", "iat": 0, "exp": i64::MAX });
+        let forged = format!(
+            "{header}.{}.{}",
+            URL_SAFE_NO_PAD.encode(forged.to_string()),
+            token.rsplit_once('.').unwrap().1
+        );
+        assert!(config().verify(&forged).is_err());
+        assert!(config().verify("not a token").is_err());
+    }
+
+    #[test]
+    fn verify_rejects_expired_tokens() {
+        let mut jwt = config();
+        jwt.expires_in = -1;
+        assert!(jwt.verify(&jwt.issue(&user()).unwrap()).is_err());
+    }
+
+    #[test]
+    fn verify_rejects_other_algorithms() {
+        let jwt = config();
+        let claims = serde_json::json!({ "sub": "7", "email": "", "iat": 0, "exp": i64::MAX });
+        assert!(jwt.verify(&sign(&jwt, HEADER, &claims)).is_ok());
+        assert!(jwt
+            .verify(&sign(&jwt, r#"{"alg":"none","typ":"JWT"}"#, &claims))
+            .is_err());
+        assert!(jwt
+            .verify(&sign(&jwt, r#"{"alg":"HS512","typ":"JWT"}"#, &claims))
+            .is_err());
+    }
+
+    #[test]
+    fn verify_password_checks_salted_hashes() {
+        let hash = hash_password("hunter2");
+        assert!(hash.starts_with("sha256$"));
+        assert!(verify_password(&hash, "hunter2"));
+        assert!(!verify_password(&hash, "hunter3"));
+        assert_ne!(hash, hash_password("hunter2"));
+    }
+
+    #[test]
+    fn verify_password_rejects_malformed_hashes() {
+        assert!(!verify_password("hunter2", "hunter2"));
+        assert!(!verify_password("md5$c2FsdA$abc", "hunter2"));
+        assert!(!verify_password("sha256$***$abc", "hunter2"));
+        assert!(!verify_password(&format!("{}$x", hash_password("a")), "a"));
+    }
+}
@@ -1,23 +1,79 @@
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, Command};
 
 // use salvo::affix;
-use salvo::cors::{self as cors, Cors};
 use salvo::prelude::*;
 use std::path::Path;
+use std::sync::Arc;
 
+use crate::auth::{AuthConfig, BearerToken};
+use crate::changes::{change_feed, ChangeFeed, TrackedStorage};
+use crate::chaos::{Chaos, Delay};
+use crate::config::FileConfig;
+use crate::history::{History, Revision, HISTORY_DIR};
+use crate::jwt::JwtConfig;
+use crate::pagination::{PageSizes, ResponseShape};
+use crate::rate_limit::{Limit, RateLimiter};
+use crate::relations::Relationship;
+use crate::routes::Routes;
+use crate::rules::Rules;
+use crate::schema::Schemas;
+use crate::storage::{
+    copy_collections, DbFileStorage, JsonFileStorage, MemoryStorage, SqliteStorage, Storage,
+    SQLITE_FILE,
+};
+use crate::timestamps::Timestamps;
+use crate::webhooks::Webhooks;
+
+mod auth;
+mod changes;
+mod chaos;
+mod conditional;
+mod config;
+mod cors;
 mod error;
+mod events;
+mod filters;
 mod handlers;
+mod history;
 mod html;
+mod jwt;
+mod ops;
+mod pagination;
+mod rate_limit;
+mod relations;
+mod routes;
+mod rules;
+mod schema;
+mod storage;
+mod timestamps;
+mod trash;
 mod utils;
+mod webhooks;
+mod websocket;
 
-#[derive(Default, Clone, Debug)]
+#[derive(Clone, Debug)]
 pub struct AppConfig {
-    pub data_dir: String,
+    pub storage: Arc<dyn Storage>,
+    pub response_shape: ResponseShape,
+    pub page_sizes: PageSizes,
+    pub relationships: Arc<[Relationship]>,
+    pub schemas: Schemas,
+    pub timestamps: Option<Timestamps>,
+    pub require_if_match: bool,
+    pub soft_delete: bool,
+    pub trust_proxy: bool,
+    pub history: Option<Arc<History>>,
+    pub changes: ChangeFeed,
+    pub webhooks: Arc<Webhooks>,
+    pub auth: AuthConfig,
+    pub rules: Rules,
+    pub chaos: Arc<Chaos>,
+    pub rate_limiter: Arc<RateLimiter>,
 }
 
 async fn init(data_dir: &str) {
     if !Path::new(data_dir).exists() {
-        std::fs::create_dir(data_dir).unwrap();
+        std::fs::create_dir_all(data_dir).unwrap();
     }
 }
 
@@ -44,14 +100,279 @@ async fn main() {
                 .help("Port that will listen to the server")
                 .required(false),
         )
+        .arg(
+            Arg::new("data-dir")
+                .short('d')
+                .long("data-dir")
+                .value_name("DIR")
+                .help("Directory holding the collections [default: ~/.static-api]")
+                .global(true)
+                .required(false),
+        )
+        .arg(
+            Arg::new("config")
+                .short('c')
+                .long("config")
+                .value_name("FILE")
+                .help("JSON file with relationships and other settings")
+                .required(false),
+        )
+        .arg(
+            Arg::new("memory")
+                .long("memory")
+                .action(ArgAction::SetTrue)
+                .help("Keep collections in memory only, seeded from --data-dir if given")
+                .conflicts_with("storage")
+                .required(false),
+        )
+        .arg(
+            Arg::new("storage")
+                .long("storage")
+                .value_name("BACKEND")
+                .value_parser(["json", "jsonl", "memory", "db", "sqlite"])
+                .default_value("json")
+                .help("Storage backend: one .json or .jsonl file per collection, memory, a single db.json, or SQLite")
+                .required(false),
+        )
+        .arg(
+            Arg::new("jsonl")
+                .long("jsonl")
+                .value_name("COLLECTION")
+                .value_delimiter(',')
+                .action(ArgAction::Append)
+                .help("Create these collections as JSON Lines files (use * for all)")
+                .global(true)
+                .required(false),
+        )
+        .arg(
+            Arg::new("response-shape")
+                .long("response-shape")
+                .value_name("SHAPE")
+                .value_parser(["envelope", "array"])
+                .default_value("envelope")
+                .help("Default body of GET /api/<collection>: {data,total,limit,skip} or a bare array")
+                .required(false),
+        )
+        .arg(
+            Arg::new("default-limit")
+                .long("default-limit")
+                .value_name("N")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("30")
+                .help("Page size of GET /api/<collection> when no limit is given")
+                .required(false),
+        )
+        .arg(
+            Arg::new("max-limit")
+                .long("max-limit")
+                .value_name("N")
+                .value_parser(clap::value_parser!(usize))
+                .help("Largest page size a client may ask for (limit=all still returns everything)")
+                .required(false),
+        )
+        .arg(
+            Arg::new("schemas")
+                .long("schemas")
+                .value_name("DIR")
+                .help("Directory holding <collection>.schema.json files [default: the data directory]")
+                .required(false),
+        )
+        .arg(
+            Arg::new("timestamps")
+                .long("timestamps")
+                .action(ArgAction::SetTrue)
+                .help("Stamp items with createdAt, updatedAt and version fields")
+                .required(false),
+        )
+        .arg(
+            Arg::new("require-if-match")
+                .long("require-if-match")
+                .action(ArgAction::SetTrue)
+                .help("Reject PUT, PATCH and DELETE requests without an If-Match header")
+                .required(false),
+        )
+        .arg(
+            Arg::new("soft-delete")
+                .long("soft-delete")
+                .action(ArgAction::SetTrue)
+                .help("Make DELETE set deletedAt on items instead of removing them")
+                .required(false),
+        )
+        .arg(
+            Arg::new("history")
+                .long("history")
+                .action(ArgAction::SetTrue)
+                .help("Record every change to items, kept in .history inside the data directory")
+                .required(false),
+        )
+        .arg(
+            Arg::new("api-key")
+                .long("api-key")
+                .value_name("KEY")
+                .action(ArgAction::Append)
+                .help("Require API requests to carry this X-API-Key (repeatable)")
+                .required(false),
+        )
+        .arg(
+            Arg::new("token")
+                .long("token")
+                .value_name("TOKEN")
+                .action(ArgAction::Append)
+                .help("Require API requests to carry this bearer token (repeatable)")
+                .required(false),
+        )
+        .arg(
+            Arg::new("cors-origin")
+                .long("cors-origin")
+                .value_name("ORIGIN")
+                .value_delimiter(',')
+                .action(ArgAction::Append)
+                .help("Origins allowed to make cross-origin requests [default: *]")
+                .required(false),
+        )
+        .arg(
+            Arg::new("cors-methods")
+                .long("cors-methods")
+                .value_name("METHODS")
+                .value_delimiter(',')
+                .action(ArgAction::Append)
+                .help("Methods allowed in cross-origin requests [default: *]")
+                .required(false),
+        )
+        .arg(
+            Arg::new("cors-headers")
+                .long("cors-headers")
+                .value_name("HEADERS")
+                .value_delimiter(',')
+                .action(ArgAction::Append)
+                .help("Request headers allowed in cross-origin requests [default: *]")
+                .required(false),
+        )
+        .arg(
+            Arg::new("cors-credentials")
+                .long("cors-credentials")
+                .action(ArgAction::SetTrue)
+                .help("Allow credentialed cross-origin requests (cookies, Authorization)")
+                .required(false),
+        )
+        .arg(
+            Arg::new("cors-max-age")
+                .long("cors-max-age")
+                .value_name("SECONDS")
+                .value_parser(clap::value_parser!(u64))
+                .help("How long browsers may cache preflight responses")
+                .required(false),
+        )
+        .arg(
+            Arg::new("delay")
+                .long("delay")
+                .value_name("DELAY")
+                .value_parser(clap::value_parser!(Delay))
+                .help("Delay every response, e.g. 300ms, 2s or 200..800ms")
+                .required(false),
+        )
+        .arg(
+            Arg::new("failure-rate")
+                .long("failure-rate")
+                .value_name("PERCENT")
+                .value_parser(clap::value_parser!(f64))
+                .help("Answer this percentage of requests with a failure instead")
+                .required(false),
+        )
+        .arg(
+            Arg::new("failure-status")
+                .long("failure-status")
+                .value_name("CODES")
+                .value_delimiter(',')
+                .action(ArgAction::Append)
+                .value_parser(clap::value_parser!(u16))
+                .help("Statuses of the injected failures [default: 500]")
+                .required(false),
+        )
+        .arg(
+            Arg::new("drop-rate")
+                .long("drop-rate")
+                .value_name("PERCENT")
+                .value_parser(clap::value_parser!(f64))
+                .help("Drop the connection of this percentage of requests")
+                .required(false),
+        )
+        .arg(
+            Arg::new("rate-limit")
+                .long("rate-limit")
+                .value_name("LIMIT")
+                .value_parser(clap::value_parser!(Limit))
+                .help("Requests each client may make per window, e.g. 100/1m")
+                .required(false),
+        )
+        .arg(
+            Arg::new("trust-proxy")
+                .long("trust-proxy")
+                .action(ArgAction::SetTrue)
+                .help("Take client addresses from the X-Forwarded-For header set by a reverse proxy")
+                .required(false),
+        )
+        .arg(
+            Arg::new("rules")
+                .long("rules")
+                .value_name("FILE")
+                .help("JSON file with the access rules of each collection")
+                .required(false),
+        )
+        .arg(
+            Arg::new("routes")
+                .long("routes")
+                .value_name("FILE")
+                .help("JSON file mapping custom paths to the routes of the API")
+                .required(false),
+        )
+        .arg(
+            Arg::new("jwt-secret")
+                .long("jwt-secret")
+                .value_name("SECRET")
+                .help("Enable /auth/register and /auth/login, signing their JWTs with this secret")
+                .required(false),
+        )
+        .subcommand(
+            Command::new("import")
+                .about(format!("Import the .json/.jsonl collections into {SQLITE_FILE}")),
+        )
+        .subcommand(
+            Command::new("export")
+                .about(format!("Export the {SQLITE_FILE} collections to .json/.jsonl files")),
+        )
+        .subcommand(
+            Command::new("rollback")
+                .about("Restore a collection to an earlier revision of its history")
+                .arg(
+                    Arg::new("collection")
+                        .value_name("COLLECTION")
+                        .help("Collection to roll back")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .value_name("REVISION")
+                        .help("Revision number or RFC 3339 timestamp to go back to")
+                        .required(true),
+                ),
+        )
         .get_matches();
 
     let host = matches.get_one::<String>("host").unwrap();
     let port = matches.get_one::<String>("port").unwrap();
+    let backend = if matches.get_flag("memory") {
+        "memory"
+    } else {
+        matches.get_one::<String>("storage").unwrap().as_str()
+    };
 
     let mut data_dir = String::new();
 
-    if let Some(mut home_dir) = dirs::home_dir() {
+    if let Some(dir) = matches.get_one::<String>("data-dir") {
+        data_dir = dir.clone();
+    } else if let Some(mut home_dir) = dirs::home_dir() {
         home_dir.push(".static-api");
 
         if let Some(data_dir_str) = home_dir.to_str() {
@@ -64,36 +385,305 @@ async fn main() {
         println!("Unable to determine the user's directory.");
     }
 
-    init(&data_dir).await;
+    let mut json_lines: Vec<String> = matches
+        .get_many::<String>("jsonl")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+    if backend == "jsonl" {
+        json_lines.push("*".to_string());
+    }
+
+    if let Some((command @ ("import" | "export"), _)) = matches.subcommand() {
+        init(&data_dir).await;
+        let files = JsonFileStorage::new(&data_dir).with_json_lines(json_lines);
+        let sqlite = SqliteStorage::open(&data_dir).unwrap();
+        let (from, to): (&dyn Storage, &dyn Storage) = match command {
+            "import" => (&files, &sqlite),
+            _ => (&sqlite, &files),
+        };
+
+        for f in copy_collections(from, to).await.unwrap() {
+            println!("{command}ed {f}");
+        }
+        return;
+    }
+
+    let storage: Arc<dyn Storage> = match backend {
+        "memory" if matches.contains_id("data-dir") => {
+            Arc::new(MemoryStorage::load(&data_dir).await.unwrap())
+        }
+        "memory" => Arc::new(MemoryStorage::default()),
+        "db" => {
+            init(&data_dir).await;
+            Arc::new(DbFileStorage::new(&data_dir))
+        }
+        "sqlite" => {
+            init(&data_dir).await;
+            Arc::new(SqliteStorage::open(&data_dir).unwrap())
+        }
+        _ => {
+            init(&data_dir).await;
+            Arc::new(JsonFileStorage::new(&data_dir).with_json_lines(json_lines))
+        }
+    };
+
+    let history = if matches.get_flag("history") || matches.subcommand_name() == Some("rollback")
+    {
+        let log: Arc<dyn Storage> = if backend == "memory" {
+            Arc::new(MemoryStorage::default())
+        } else {
+            let history_dir = format!("{data_dir}/{HISTORY_DIR}");
+            init(&history_dir).await;
+            Arc::new(JsonFileStorage::new(&history_dir).with_json_lines(vec!["*".to_string()]))
+        };
+        Some(Arc::new(History::new(log)))
+    } else {
+        None
+    };
+
+    let file_config = match matches.get_one::<String>("config") {
+        Some(path) => FileConfig::load(path).unwrap(),
+        None => FileConfig::default(),
+    };
+
+    let mut auth = file_config.auth;
+    auth.api_keys.extend(
+        matches
+            .get_many::<String>("api-key")
+            .unwrap_or_default()
+            .cloned(),
+    );
+    auth.tokens.extend(
+        matches
+            .get_many::<String>("token")
+            .unwrap_or_default()
+            .cloned()
+            .map(BearerToken::new),
+    );
+    if let Some(secret) = matches.get_one::<String>("jwt-secret") {
+        match &mut auth.jwt {
+            Some(jwt) => jwt.secret = secret.clone(),
+            None => auth.jwt = Some(JwtConfig::new(secret.clone())),
+        }
+    }
+
+    let changes = change_feed();
+    let storage: Arc<dyn Storage> = Arc::new(
+        TrackedStorage::new(storage, history.clone(), changes.clone())
+            .with_users(auth.jwt.as_ref().map(|jwt| jwt.users.clone()))
+            .with_soft_delete(matches.get_flag("soft-delete")),
+    );
+
+    if let (Some(("rollback", rollback)), Some(history)) = (matches.subcommand(), &history) {
+        let f = rollback.get_one::<String>("collection").unwrap();
+        let to = Revision::parse(rollback.get_one::<String>("to").unwrap()).unwrap();
+        let changed = history.rollback(storage.as_ref(), f, &to).await.unwrap();
+        println!("rolled back {f} to {to}: {changed} items changed");
+        return;
+    }
+
+    let response_shape =
+        ResponseShape::parse(matches.get_one::<String>("response-shape").unwrap()).unwrap();
+
+    let page_sizes = PageSizes {
+        default_limit: *matches.get_one::<usize>("default-limit").unwrap(),
+        max_limit: matches.get_one::<usize>("max-limit").copied(),
+    };
+
+    let schemas = Schemas::new(
+        matches
+            .get_one::<String>("schemas")
+            .cloned()
+            // Seeded in-memory collections keep their schemas next to the seed.
+            .or_else(|| {
+                (backend != "memory" || matches.contains_id("data-dir")).then(|| data_dir.clone())
+            }),
+    );
+
+    let timestamps = file_config
+        .timestamps
+        .or_else(|| matches.get_flag("timestamps").then(Timestamps::default));
+
+    let mut chaos = file_config.chaos;
+    if let Some(delay) = matches.get_one::<Delay>("delay") {
+        chaos.delay = Some(*delay);
+    }
+    if let Some(failure_rate) = matches.get_one::<f64>("failure-rate") {
+        chaos.failure_rate = *failure_rate;
+    }
+    if let Some(statuses) = matches.get_many::<u16>("failure-status") {
+        chaos.failure_statuses = statuses.copied().collect();
+    }
+    if let Some(drop_rate) = matches.get_one::<f64>("drop-rate") {
+        chaos.drop_rate = *drop_rate;
+    }
+    chaos.validate().unwrap();
+
+    let mut rate_limit = file_config.rate_limit;
+    if let Some(limit) = matches.get_one::<Limit>("rate-limit") {
+        rate_limit.limit = Some(*limit);
+    }
+
+    let rules = match matches.get_one::<String>("rules") {
+        Some(path) => Rules::load(path, &auth).unwrap(),
+        None => Rules::default(),
+    };
+    let routes = match matches.get_one::<String>("routes") {
+        Some(path) => Arc::new(Routes::load(path).unwrap()),
+        None => Arc::new(Routes::default()),
+    };
+
+    let webhooks = Arc::new(Webhooks::new(file_config.webhooks).unwrap());
+    tokio::spawn(webhooks.clone().run(changes.subscribe()));
 
     let app_config = AppConfig {
-        data_dir: data_dir.clone(),
+        storage,
+        response_shape,
+        page_sizes,
+        relationships: file_config.relationships.into(),
+        schemas,
+        timestamps,
+        require_if_match: matches.get_flag("require-if-match"),
+        soft_delete: matches.get_flag("soft-delete"),
+        trust_proxy: matches.get_flag("trust-proxy"),
+        history,
+        changes,
+        webhooks,
+        auth,
+        rules,
+        chaos: Arc::new(chaos),
+        rate_limiter: Arc::new(RateLimiter::new(rate_limit)),
     };
 
-    let cors_handler = Cors::new()
-        .allow_origin(cors::Any)
-        .allow_methods(cors::Any)
-        .allow_headers(cors::Any)
-        .into_handler();
+    let mut cors_config = file_config.cors;
+    if let Some(origins) = matches.get_many::<String>("cors-origin") {
+        cors_config.origins = origins.cloned().collect();
+    }
+    if let Some(methods) = matches.get_many::<String>("cors-methods") {
+        cors_config.methods = methods.cloned().collect();
+    }
+    if let Some(headers) = matches.get_many::<String>("cors-headers") {
+        cors_config.headers = headers.cloned().collect();
+    }
+    if matches.get_flag("cors-credentials") {
+        cors_config.credentials = true;
+    }
+    if let Some(max_age) = matches.get_one::<u64>("cors-max-age") {
+        cors_config.max_age = Some(*max_age);
+    }
+    let cors_handler = cors_config.handler().unwrap();
 
     let router = Router::new()
+        // Custom routes are rewritten before the router looks for a handler.
+        .filter_fn(move |req, path_state| {
+            routes.rewrite(req, path_state);
+            true
+        })
         .hoop(affix_state::inject(app_config.clone()))
+        .hoop(cors_handler)
+        .hoop(cors::preflight)
+        .hoop(chaos::inject)
+        .hoop(history::track_client)
+        .options(handler::empty())
         .get(html::index)
-        .push(Router::with_path("delete-collection/{f}").get(html::delete_collection))
         .push(
-            Router::with_path("api/{f}")
-                .hoop(cors_handler.clone())
+            Router::with_path("delete-collection/{f}")
+                .hoop(auth::authenticate)
+                .hoop(auth::require_credentials)
                 .options(handler::empty())
-                .get(handlers::get_all)
-                .post(handlers::add_one),
+                .get(html::delete_collection),
+        )
+        .push(
+            Router::with_path("ws")
+                .hoop(auth::authenticate)
+                .goal(websocket::connect),
         )
         .push(
-            Router::with_path("api/{f}/{id}")
-                .hoop(cors_handler.clone())
+            Router::with_path("_webhooks")
+                .hoop(auth::authenticate)
+                .hoop(auth::require_credentials)
                 .options(handler::empty())
-                .get(handlers::get_one)
-                .put(handlers::update_one)
-                .delete(handlers::delete_one),
+                .get(webhooks::list_webhooks)
+                .post(webhooks::add_webhook)
+                .push(
+                    Router::with_path("deliveries")
+                        .options(handler::empty())
+                        .get(webhooks::list_deliveries),
+                )
+                .push(
+                    Router::with_path("{id}")
+                        .options(handler::empty())
+                        .delete(webhooks::delete_webhook),
+                ),
+        )
+        .push(
+            Router::with_path("auth")
+                .push(
+                    Router::with_path("register")
+                        .options(handler::empty())
+                        .post(jwt::register),
+                )
+                .push(
+                    Router::with_path("login")
+                        .options(handler::empty())
+                        .post(jwt::login),
+                ),
+        )
+        .push(
+            Router::with_path("api")
+                .hoop(auth::authenticate)
+                .hoop(rate_limit::throttle)
+                .hoop(rules::enforce)
+                .push(
+                    Router::with_path("{f}")
+                        .options(handler::empty())
+                        .get(handlers::get_all)
+                        .post(handlers::add_one),
+                )
+                .push(
+                    Router::with_path("{f}/_trash")
+                        .options(handler::empty())
+                        .get(handlers::get_trash),
+                )
+                .push(
+                    Router::with_path("{f}/_history")
+                        .options(handler::empty())
+                        .get(handlers::get_changelog),
+                )
+                .push(
+                    Router::with_path("{f}/_rollback")
+                        .options(handler::empty())
+                        .post(handlers::rollback),
+                )
+                .push(
+                    Router::with_path("{f}/_events")
+                        .options(handler::empty())
+                        .get(events::collection_events),
+                )
+                .push(
+                    Router::with_path("{f}/{id}")
+                        .options(handler::empty())
+                        .get(handlers::get_one)
+                        .put(handlers::update_one)
+                        .patch(handlers::patch_one)
+                        .delete(handlers::delete_one),
+                )
+                .push(
+                    Router::with_path("{f}/{id}/_restore")
+                        .options(handler::empty())
+                        .post(handlers::restore_one),
+                )
+                .push(
+                    Router::with_path("{f}/{id}/_history")
+                        .options(handler::empty())
+                        .get(handlers::get_history),
+                )
+                .push(
+                    Router::with_path("{f}/{id}/{child}")
+                        .options(handler::empty())
+                        .get(handlers::get_children),
+                ),
         );
     let acceptor = TcpListener::new(format!("{host}:{port}")).bind().await;
     println!("Welcome to static-api!");
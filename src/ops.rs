@@ -0,0 +1,286 @@
+use salvo::http::Method;
+
+use crate::auth::Caller;
+use crate::error::{AppError, AppResult};
+use crate::jwt::{strip_password, Claims};
+use crate::relations::delete_with_references;
+use crate::rules::Access;
+use crate::timestamps::TimestampFormat;
+use crate::trash::is_deleted;
+use crate::utils::merge_patch;
+use crate::AppConfig;
+
+/// Whether `item` is soft-deleted and should look missing to clients that
+/// did not ask for deleted items.
+pub fn hidden(app_config: &AppConfig, item: &serde_json::Value, with_deleted: bool) -> bool {
+    app_config.soft_delete && is_deleted(item) && !with_deleted
+}
+
+/// Item `id` of `f` as PUT and PATCH requests see it: soft-deleted items are
+/// missing until they are restored.
+pub async fn current(app_config: &AppConfig, f: &str, id: u64) -> Option<serde_json::Value> {
+    let item = app_config.storage.get(f, id).await.ok()?;
+    (!hidden(app_config, &item, false)).then_some(item)
+}
+
+/// Field holding the id of the user owning each item of `f`, for collections
+/// whose items have owners.
+pub fn owner_field<'a>(app_config: &'a AppConfig, f: &str) -> Option<&'a str> {
+    app_config
+        .auth
+        .jwt
+        .as_ref()?
+        .owners
+        .get(f)
+        .map(String::as_str)
+}
+
+/// Whether `f` holds the users of the login flow.
+fn is_users(app_config: &AppConfig, f: &str) -> bool {
+    app_config
+        .auth
+        .jwt
+        .as_ref()
+        .is_some_and(|jwt| jwt.users == f)
+}
+
+/// Who may make `method` requests on `f`: as set by the rules of `f`, or
+/// else nobody for writes to the users of the login flow, which go through
+/// `/auth`, and only the owners of items for collections whose items have
+/// owners, then as set by the default `*` rules, then only clients with
+/// credentials when authentication is on.
+pub fn access(app_config: &AppConfig, f: &str, method: &Method) -> Access {
+    let rules = &app_config.rules;
+    if let Some(access) = rules.access(f, method) {
+        return access;
+    }
+    if is_users(app_config, f) && !matches!(*method, Method::GET | Method::HEAD) {
+        return Access::Denied;
+    }
+    if owner_field(app_config, f).is_some() {
+        return Access::Owner;
+    }
+    rules
+        .access("*", method)
+        .unwrap_or(if app_config.auth.is_enabled() {
+            Access::Authenticated
+        } else {
+            Access::Public
+        })
+}
+
+/// Checks that `caller` may make `method` requests on `f` at all. Which
+/// items of `f` an owner may access is checked by `check_owner`.
+pub fn check_access(
+    app_config: &AppConfig,
+    f: &str,
+    method: &Method,
+    caller: &Caller,
+) -> AppResult<()> {
+    match (access(app_config, f, method), caller) {
+        (Access::Public, _) | (Access::Authenticated, Caller::Client | Caller::User(_)) => Ok(()),
+        (Access::Owner, Caller::User(_)) => Ok(()),
+        (Access::Authenticated | Access::Owner, Caller::Anonymous) => Err(AppError::Unauthorized(
+            format!("`{method}` requests on `{f}` need credentials"),
+        )),
+        (Access::Owner, Caller::Client) => Err(AppError::Forbidden(format!(
+            "`{method}` requests on `{f}` can only be made by signed-in users"
+        ))),
+        (Access::Denied, _) => Err(AppError::Forbidden(format!(
+            "`{method}` requests on `{f}` are not allowed"
+        ))),
+    }
+}
+
+/// Removes what no client may read from `item` of `f`: the password hash of
+/// users of the login flow.
+pub fn redact(app_config: &AppConfig, f: &str, item: &mut serde_json::Value) {
+    if is_users(app_config, f) {
+        strip_password(item);
+    }
+}
+
+/// `item` of `f` as clients see it.
+pub fn redacted(app_config: &AppConfig, f: &str, item: &serde_json::Value) -> serde_json::Value {
+    let mut item = item.clone();
+    redact(app_config, f, &mut item);
+    item
+}
+
+/// Whether `item` of `f` is owned by someone other than `user`, in a
+/// collection only readable by owners.
+pub fn foreign(
+    app_config: &AppConfig,
+    f: &str,
+    item: &serde_json::Value,
+    user: Option<&Claims>,
+) -> bool {
+    owns_items(app_config, f, &Method::GET, item, user) == Some(false)
+}
+
+/// Whether `user` owns `item` of `f`, when `method` requests on `f` are
+/// restricted to owners.
+fn owns_items(
+    app_config: &AppConfig,
+    f: &str,
+    method: &Method,
+    item: &serde_json::Value,
+    user: Option<&Claims>,
+) -> Option<bool> {
+    if access(app_config, f, method) != Access::Owner {
+        return None;
+    }
+    let field = owner_field(app_config, f)?;
+    Some(user.is_some_and(|user| item[field] == user.owner()))
+}
+
+/// Checks that `user` may make `method` requests on item `current` of `f`.
+pub fn check_owner(
+    app_config: &AppConfig,
+    f: &str,
+    method: &Method,
+    current: Option<&serde_json::Value>,
+    user: Option<&Claims>,
+) -> AppResult<()> {
+    match current {
+        Some(item) if owns_items(app_config, f, method, item, user) == Some(false) => {
+            Err(AppError::Forbidden(format!(
+                "item {} of `{f}` belongs to another user",
+                item["id"]
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Sets the owner field of an item of `f` about to be written by `user`.
+fn claim(app_config: &AppConfig, f: &str, item: &mut serde_json::Value, user: Option<&Claims>) {
+    let (Some(field), Some(user), Some(fields)) =
+        (owner_field(app_config, f), user, item.as_object_mut())
+    else {
+        return;
+    };
+    fields.insert(field.to_string(), user.owner());
+}
+
+/// Stamps, validates and stores a new item of `f`, owned by `user` when
+/// items of `f` have owners.
+pub async fn create(
+    app_config: &AppConfig,
+    f: &str,
+    mut new_item: serde_json::Value,
+    user: Option<&Claims>,
+) -> AppResult<serde_json::Value> {
+    claim(app_config, f, &mut new_item, user);
+    if let Some(timestamps) = &app_config.timestamps {
+        timestamps.stamp_new(&mut new_item);
+    }
+    app_config.schemas.validate(f, &new_item).await?;
+    app_config.storage.insert(f, new_item).await
+}
+
+/// Replaces item `id` of `f`, currently `previous`, with `updated_item`.
+/// Returns the stored item, or `None` when there was nothing to replace.
+/// When `conditional`, the item must still be `previous` when written.
+pub async fn replace(
+    app_config: &AppConfig,
+    f: &str,
+    id: u64,
+    mut updated_item: serde_json::Value,
+    previous: Option<serde_json::Value>,
+    user: Option<&Claims>,
+    conditional: bool,
+) -> AppResult<Option<serde_json::Value>> {
+    let Some(previous) = previous else {
+        return Ok(None);
+    };
+    claim(app_config, f, &mut updated_item, user);
+    if let Some(timestamps) = &app_config.timestamps {
+        timestamps.stamp_replacement(&mut updated_item, &previous);
+    }
+    app_config.schemas.validate(f, &updated_item).await?;
+
+    let expected = conditional.then_some(&previous);
+    let found_item = app_config
+        .storage
+        .replace_if(f, id, expected, &updated_item)
+        .await?;
+    Ok(found_item.then_some(updated_item))
+}
+
+/// Applies merge patch `patch` to item `id` of `f`, currently `current`.
+/// Returns the patched item, or `None` when there was nothing to patch.
+/// Only objects are accepted as patches, items being objects themselves.
+/// When `conditional`, the item must still be `current` when patched.
+pub async fn patch(
+    app_config: &AppConfig,
+    f: &str,
+    id: u64,
+    mut patch: serde_json::Value,
+    current: Option<serde_json::Value>,
+    user: Option<&Claims>,
+    conditional: bool,
+) -> AppResult<Option<serde_json::Value>> {
+    if !patch.is_object() {
+        return Err(AppError::BadRequest(
+            "merge patches must be JSON objects".to_string(),
+        ));
+    }
+    let Some(current) = current else {
+        return Ok(None);
+    };
+    claim(app_config, f, &mut patch, user);
+    if let Some(timestamps) = &app_config.timestamps {
+        timestamps.stamp_patch(&mut patch, &current);
+    }
+
+    let expected = conditional.then(|| current.clone());
+
+    // The schema applies to the item as it will be stored, not to the patch.
+    let mut patched_item = current;
+    merge_patch(&mut patched_item, &patch);
+    patched_item["id"] = serde_json::Value::from(id);
+    app_config.schemas.validate(f, &patched_item).await?;
+
+    app_config
+        .storage
+        .patch_if(f, id, expected.as_ref(), &patch)
+        .await
+}
+
+/// Deletes item `id` of `f`, currently `current`, along with the references
+/// to it. With soft deletes on, the item is only marked unless `purge`.
+/// When `conditional`, the item must still be `current` when deleted.
+pub async fn delete(
+    app_config: &AppConfig,
+    f: &str,
+    id: u64,
+    current: Option<&serde_json::Value>,
+    purge: bool,
+    conditional: bool,
+) -> AppResult<bool> {
+    // Soft-deleted items are already gone as far as clients are concerned,
+    // until they are purged.
+    let deleted_at = if app_config.soft_delete && !purge {
+        if current.is_some_and(is_deleted) {
+            return Ok(false);
+        }
+        let format = app_config
+            .timestamps
+            .as_ref()
+            .map_or_else(TimestampFormat::default, |timestamps| timestamps.format);
+        Some(format.now())
+    } else {
+        None
+    };
+
+    delete_with_references(
+        app_config.storage.as_ref(),
+        &app_config.relationships,
+        f,
+        id,
+        current.filter(|_| conditional),
+        deleted_at.as_ref(),
+    )
+    .await
+}
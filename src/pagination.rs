@@ -0,0 +1,358 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use salvo::prelude::*;
+
+use crate::error::{AppError, AppResult};
+use crate::storage::item_id;
+
+/// Query parameters that select the page, and are rewritten in `Link` URLs.
+pub const PAGE_PARAMS: [&str; 5] = ["skip", "limit", "page", "per_page", "cursor"];
+
+/// How `get_all` shapes its body: the `{data,total,limit,skip}` envelope,
+/// or a bare array with `X-Total-Count` and `Link` headers.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ResponseShape {
+    #[default]
+    Envelope,
+    Array,
+}
+
+impl ResponseShape {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "envelope" => Some(Self::Envelope),
+            "array" => Some(Self::Array),
+            _ => None,
+        }
+    }
+
+    /// The shape asked for by `?_shape=` or the `X-Response-Shape` header,
+    /// falling back to `default`.
+    pub fn from_request(req: &Request, default: Self) -> AppResult<Self> {
+        let requested = req
+            .query::<String>("_shape")
+            .or_else(|| req.header::<String>("x-response-shape"));
+
+        match requested {
+            Some(value) => Self::parse(&value)
+                .ok_or_else(|| AppError::BadRequest(format!("invalid response shape `{value}`"))),
+            None => Ok(default),
+        }
+    }
+}
+
+/// Page sizes applied by `get_all` when `limit`/`per_page` is missing, and
+/// the largest one a client may ask for.
+#[derive(Clone, Copy, Debug)]
+pub struct PageSizes {
+    pub default_limit: usize,
+    pub max_limit: Option<usize>,
+}
+
+/// The slice of a collection requested by `get_all`, from `skip`/`limit`,
+/// `page`/`per_page` or `cursor`/`limit` (in increasing precedence).
+///
+/// A `limit` of `None` (`limit=all` or `limit=0`) returns every item.
+#[derive(Debug)]
+pub struct Pagination {
+    pub skip: usize,
+    pub limit: Option<usize>,
+    /// Where a `cursor` points, only resolved to `skip` against the items
+    /// being paginated.
+    cursor: Option<Cursor>,
+}
+
+impl Pagination {
+    pub fn from_request(req: &Request, page_sizes: PageSizes) -> AppResult<Self> {
+        let limit = match req
+            .query::<String>("per_page")
+            .or_else(|| req.query::<String>("limit"))
+        {
+            Some(value) if value == "all" || value == "0" => None,
+            Some(value) => {
+                let limit = parse_count("limit", &value).map_err(|_| {
+                    AppError::BadRequest(format!(
+                        "invalid `limit` value `{value}`: expected a non-negative integer or `all`"
+                    ))
+                })?;
+                Some(page_sizes.max_limit.map_or(limit, |max| limit.min(max)))
+            }
+            None => Some(page_sizes.default_limit),
+        };
+
+        let cursor = req
+            .query::<String>("cursor")
+            .map(|cursor| {
+                Cursor::decode(&cursor)
+                    .ok_or_else(|| AppError::BadRequest(format!("invalid cursor `{cursor}`")))
+            })
+            .transpose()?;
+
+        let skip = if cursor.is_some() {
+            0
+        } else if let Some(page) = req.query::<String>("page") {
+            match parse_count("page", &page)? {
+                0 => return Err(AppError::BadRequest("`page` starts at 1".to_string())),
+                page => (page - 1).saturating_mul(limit.unwrap_or(0)),
+            }
+        } else if let Some(skip) = req.query::<String>("skip") {
+            parse_count("skip", &skip)?
+        } else {
+            0
+        };
+
+        Ok(Self {
+            skip,
+            limit,
+            cursor,
+        })
+    }
+
+    /// The page of `items` asked for, `skip` being updated to its offset
+    /// when it was asked for with a cursor.
+    pub fn apply(&mut self, items: Vec<serde_json::Value>) -> Vec<serde_json::Value> {
+        if let Some(cursor) = self.cursor.take() {
+            self.skip = cursor.skip(&items, self.limit);
+        }
+        let items = items.into_iter().skip(self.skip);
+        match self.limit {
+            Some(limit) => items.take(limit).collect(),
+            None => items.collect(),
+        }
+    }
+
+    /// Offset of the page following this one, if there is any.
+    fn next_skip(&self, total: usize) -> Option<usize> {
+        let next = self.skip.saturating_add(self.limit?);
+        (next < total).then_some(next)
+    }
+
+    /// Offset of the page preceding this one, if there is any.
+    fn prev_skip(&self) -> Option<usize> {
+        (self.skip > 0).then(|| self.skip.saturating_sub(self.limit.unwrap_or(self.skip)))
+    }
+
+    /// Cursor of the page following `page`, if there is any.
+    pub fn next_cursor(&self, total: usize, page: &[serde_json::Value]) -> Option<String> {
+        let next = self.next_skip(total)?;
+        Some(Cursor::after(next - 1, page.last()).encode())
+    }
+
+    /// Cursor of the page preceding `page`, if there is any.
+    pub fn prev_cursor(&self, page: &[serde_json::Value]) -> Option<String> {
+        self.prev_skip()?;
+        Some(Cursor::before(self.skip, page.first()).encode())
+    }
+
+    /// RFC 5988 `Link` header pointing at the first, previous, next and last
+    /// pages, keeping every other query parameter of `req`.
+    pub fn link_header(&self, req: &Request, total: usize) -> String {
+        let query: Vec<&str> = req
+            .uri()
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .filter(|pair| {
+                let key = pair.split('=').next().unwrap_or_default();
+                !pair.is_empty() && !PAGE_PARAMS.contains(&key)
+            })
+            .collect();
+        let limit = self
+            .limit
+            .map_or_else(|| "all".to_string(), |limit| limit.to_string());
+        let link = |skip: usize, rel: &str| {
+            let mut params = query.clone();
+            let page = format!("skip={skip}&limit={limit}");
+            params.push(&page);
+            format!("<{}?{}>; rel=\"{rel}\"", req.uri().path(), params.join("&"))
+        };
+
+        let mut links = vec![link(0, "first")];
+        if let Some(prev) = self.prev_skip() {
+            links.push(link(prev, "prev"));
+        }
+        if let Some(next) = self.next_skip(total) {
+            links.push(link(next, "next"));
+        }
+        if let Some(limit) = self.limit.filter(|limit| *limit > 0) {
+            links.push(link(total.saturating_sub(1) / limit * limit, "last"));
+        }
+        links.join(", ")
+    }
+}
+
+fn parse_count(name: &str, value: &str) -> AppResult<usize> {
+    value.parse().map_err(|_| {
+        AppError::BadRequest(format!(
+            "invalid `{name}` value `{value}`: expected a non-negative integer"
+        ))
+    })
+}
+
+/// A page boundary handed out as `next_cursor` or `prev_cursor`: just after
+/// the last item of a page or just before its first one. The boundary item
+/// is looked up by id, so that pages do not shift when items are added or
+/// removed before it, and by the offset it had when it is gone.
+#[derive(Debug, PartialEq)]
+struct Cursor {
+    after: bool,
+    offset: usize,
+    id: Option<u64>,
+}
+
+impl Cursor {
+    fn after(offset: usize, item: Option<&serde_json::Value>) -> Self {
+        Self {
+            after: true,
+            offset,
+            id: item.and_then(item_id),
+        }
+    }
+
+    fn before(offset: usize, item: Option<&serde_json::Value>) -> Self {
+        Self {
+            after: false,
+            offset,
+            id: item.and_then(item_id),
+        }
+    }
+
+    fn encode(&self) -> String {
+        let direction = if self.after { "after" } else { "before" };
+        let id = self.id.map(|id| id.to_string()).unwrap_or_default();
+        URL_SAFE_NO_PAD.encode(format!("{direction}:{}:{id}", self.offset))
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let decoded = String::from_utf8(URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()?;
+        let mut parts = decoded.split(':');
+        let after = match parts.next()? {
+            "after" => true,
+            "before" => false,
+            _ => return None,
+        };
+        let offset = parts.next()?.parse().ok()?;
+        let id = match parts.next()? {
+            "" => None,
+            id => Some(id.parse().ok()?),
+        };
+        parts.next().is_none().then_some(Self { after, offset, id })
+    }
+
+    /// Offset of the page this cursor leads to in `items`.
+    fn skip(&self, items: &[serde_json::Value], limit: Option<usize>) -> usize {
+        let offset = self
+            .id
+            .and_then(|id| items.iter().position(|item| item_id(item) == Some(id)))
+            .unwrap_or(self.offset);
+        if self.after {
+            offset.saturating_add(1)
+        } else {
+            offset.saturating_sub(limit.unwrap_or(offset))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const PAGE_SIZES: PageSizes = PageSizes {
+        default_limit: 2,
+        max_limit: None,
+    };
+
+    fn request(uri: &str) -> Request {
+        let mut req = Request::new();
+        req.set_uri(uri.parse().unwrap());
+        req
+    }
+
+    fn items(ids: &[u64]) -> Vec<serde_json::Value> {
+        ids.iter().map(|id| json!({ "id": id })).collect()
+    }
+
+    fn page(uri: &str, ids: &[u64]) -> (Pagination, Vec<serde_json::Value>) {
+        let mut pagination = Pagination::from_request(&request(uri), PAGE_SIZES).unwrap();
+        let page = pagination.apply(items(ids));
+        (pagination, page)
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        for cursor in [
+            Cursor::after(3, Some(&json!({ "id": 17 }))),
+            Cursor::before(0, Some(&json!({ "id": 5 }))),
+            Cursor::after(8, None),
+        ] {
+            assert_eq!(Cursor::decode(&cursor.encode()), Some(cursor));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_cursors() {
+        for cursor in ["", "!!", "b2Zmc2V0OjQ", "after:1:2", "YWZ0ZXI6eDo"] {
+            assert_eq!(Cursor::decode(cursor), None);
+        }
+        assert!(Pagination::from_request(&request("/api/posts?cursor=abc"), PAGE_SIZES).is_err());
+    }
+
+    #[test]
+    fn cursors_lead_to_the_adjacent_pages() {
+        let ids = [1, 2, 3, 4, 5];
+        let (pagination, first) = page("/api/posts", &ids);
+        assert_eq!(first, items(&[1, 2]));
+        assert_eq!(pagination.prev_cursor(&first), None);
+
+        let next = pagination.next_cursor(ids.len(), &first).unwrap();
+        let (pagination, second) = page(&format!("/api/posts?cursor={next}"), &ids);
+        assert_eq!(second, items(&[3, 4]));
+        assert_eq!(pagination.skip, 2);
+
+        let prev = pagination.prev_cursor(&second).unwrap();
+        let (_, back) = page(&format!("/api/posts?cursor={prev}"), &ids);
+        assert_eq!(back, first);
+
+        let next = pagination.next_cursor(ids.len(), &second).unwrap();
+        let (pagination, last) = page(&format!("/api/posts?cursor={next}"), &ids);
+        assert_eq!(last, items(&[5]));
+        assert_eq!(pagination.next_cursor(ids.len(), &last), None);
+    }
+
+    #[test]
+    fn cursors_follow_their_item() {
+        let (pagination, first) = page("/api/posts", &[1, 2, 3, 4]);
+        let next = pagination.next_cursor(4, &first).unwrap();
+
+        // Items added before the cursor do not shift the next page.
+        let (_, second) = page(&format!("/api/posts?cursor={next}"), &[9, 1, 2, 3, 4]);
+        assert_eq!(second, items(&[3, 4]));
+
+        // Without its item, the cursor falls back to the offset it had.
+        let (_, second) = page(&format!("/api/posts?cursor={next}"), &[1, 3, 4, 5]);
+        assert_eq!(second, items(&[4, 5]));
+    }
+
+    #[test]
+    fn link_header_points_at_every_page() {
+        let (pagination, _) = page("/api/posts?title=a&skip=2&limit=2", &[1, 2, 3, 4, 5]);
+        assert_eq!(
+            pagination.link_header(&request("/api/posts?title=a&skip=2&limit=2"), 5),
+            "</api/posts?title=a&skip=0&limit=2>; rel=\"first\", \
+             </api/posts?title=a&skip=0&limit=2>; rel=\"prev\", \
+             </api/posts?title=a&skip=4&limit=2>; rel=\"next\", \
+             </api/posts?title=a&skip=4&limit=2>; rel=\"last\""
+        );
+    }
+
+    #[test]
+    fn link_header_of_a_single_page() {
+        let req = request("/api/posts?limit=all");
+        let (pagination, _) = page("/api/posts?limit=all", &[1, 2]);
+        assert_eq!(
+            pagination.link_header(&req, 2),
+            "</api/posts?skip=0&limit=all>; rel=\"first\""
+        );
+    }
+}
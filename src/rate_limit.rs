@@ -0,0 +1,271 @@
+use salvo::prelude::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::auth::{bearer_token, caller, Caller};
+use crate::error::{AppError, AppResult};
+use crate::history::client_address;
+use crate::AppConfig;
+
+/// How many buckets are kept before the full ones are forgotten.
+const MAX_BUCKETS: usize = 10_000;
+
+/// Longest window of a limit.
+const MAX_WINDOW: Duration = Duration::from_secs(24 * 3600);
+
+/// Requests a client may make per window: a burst of `requests`, then one
+/// more every `window / requests`.
+///
+/// Written `<requests>/<window>`, the window being a number of seconds,
+/// minutes or hours such as `100/1m`, `5/10s` or `10/s`, of at most a day.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Limit {
+    requests: u32,
+    window: Duration,
+}
+
+impl TryFrom<String> for Limit {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl FromStr for Limit {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid rate limit `{value}`: expected e.g. `100/1m`, `5/10s` or `10/s`, \
+                 with a window of at most 24h"
+            )
+        };
+        let (requests, window) = value.split_once('/').ok_or_else(invalid)?;
+        let requests = requests.trim().parse::<u32>().map_err(|_| invalid())?;
+
+        let window = window.trim();
+        let (count, unit) = match window.char_indices().last() {
+            Some((at, 's')) => (&window[..at], 1),
+            Some((at, 'm')) => (&window[..at], 60),
+            Some((at, 'h')) => (&window[..at], 3600),
+            _ => return Err(invalid()),
+        };
+        let count = if count.is_empty() {
+            1
+        } else {
+            count.parse::<u64>().map_err(|_| invalid())?
+        };
+
+        let window = Duration::from_secs(count.checked_mul(unit).ok_or_else(invalid)?);
+        if requests == 0 || window.is_zero() || window > MAX_WINDOW {
+            return Err(invalid());
+        }
+        Ok(Self { requests, window })
+    }
+}
+
+impl Limit {
+    /// Tokens given back per second.
+    fn rate(&self) -> f64 {
+        f64::from(self.requests) / self.window.as_secs_f64()
+    }
+}
+
+/// Rate limits of the API, from the `rate_limit` section of the `--config`
+/// file and `--rate-limit`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Limit of the collections without one of their own.
+    pub limit: Option<Limit>,
+    /// Limits of given collections, each counted separately.
+    pub collections: HashMap<String, Limit>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// When the bucket will be full again, after which it can be forgotten.
+    full_at: Instant,
+}
+
+/// The state of a bucket once a request has been counted.
+struct Quota {
+    remaining: u32,
+    /// Seconds until the bucket is full again.
+    reset: u64,
+    /// Seconds until the next request is allowed, when this one was not.
+    retry_after: Option<u64>,
+}
+
+/// Token buckets of every client, by collection for the collections with
+/// their own limit.
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<HashMap<(String, String), Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// The limit of `f`, and the name its requests are counted under.
+    fn limit<'a>(&self, f: Option<&'a str>) -> Option<(Limit, &'a str)> {
+        match f.and_then(|f| Some((*self.config.collections.get(f)?, f))) {
+            Some(limit) => Some(limit),
+            None => self.config.limit.map(|limit| (limit, "*")),
+        }
+    }
+
+    /// Takes a token from the bucket of `client` for `scope`.
+    fn take(&self, client: String, scope: &str, limit: Limit) -> Quota {
+        let now = Instant::now();
+        let capacity = f64::from(limit.requests);
+        let rate = limit.rate();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, bucket| bucket.full_at > now);
+        }
+        let bucket = buckets
+            .entry((client, scope.to_string()))
+            .or_insert(Bucket {
+                tokens: capacity,
+                updated: now,
+                full_at: now,
+            });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated = now;
+
+        let retry_after = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(((1.0 - bucket.tokens) / rate).ceil() as u64)
+        };
+        let reset =
+            Duration::try_from_secs_f64((capacity - bucket.tokens) / rate).unwrap_or(limit.window);
+        bucket.full_at = now.checked_add(reset).unwrap_or(now);
+        Quota {
+            remaining: bucket.tokens as u32,
+            reset: reset.as_secs_f64().ceil() as u64,
+            retry_after,
+        }
+    }
+
+    /// Counts a WebSocket message of `client` on `f` as a request, turning
+    /// it away as `throttle` does once the bucket is empty.
+    pub fn check(&self, client: &str, f: &str) -> AppResult<()> {
+        let Some((limit, scope)) = self.limit(Some(f)) else {
+            return Ok(());
+        };
+        match self.take(client.to_string(), scope, limit).retry_after {
+            Some(retry_after) => Err(AppError::TooManyRequests(retry_after)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Who requests are counted for: the user, API key or token they come
+/// with, or else their address.
+pub fn client(req: &Request, caller: &Caller, trust_proxy: bool) -> String {
+    if let Some(user) = caller.user() {
+        return format!("user {}", user.sub);
+    }
+    if matches!(caller, Caller::Client) {
+        if let Some(key) = req.header::<String>("x-api-key") {
+            return format!("key {key}");
+        }
+        if let Some(token) = bearer_token(req) {
+            return format!("token {token}");
+        }
+    }
+    format!(
+        "address {}",
+        client_address(req, trust_proxy).unwrap_or_default()
+    )
+}
+
+/// Counts requests against the rate limit of their collection, telling
+/// clients where they stand in `RateLimit-*` headers, and turns them away
+/// with `429 Too Many Requests` once their bucket is empty.
+#[handler]
+pub async fn throttle(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let app_config = depot.obtain::<AppConfig>().unwrap();
+    let f = req.param::<String>("f");
+    let Some((limit, scope)) = app_config.rate_limiter.limit(f.as_deref()) else {
+        return Ok(());
+    };
+
+    let client = client(req, &caller(depot), app_config.trust_proxy);
+    let quota = app_config.rate_limiter.take(client, scope, limit);
+    let _ = res.add_header("ratelimit-limit", limit.requests, true);
+    let _ = res.add_header("ratelimit-remaining", quota.remaining, true);
+    let _ = res.add_header("ratelimit-reset", quota.reset, true);
+    let _ = res.add_header(
+        "ratelimit-policy",
+        format!("{};w={}", limit.requests, limit.window.as_secs()),
+        true,
+    );
+
+    match quota.retry_after {
+        Some(retry_after) => Err(AppError::TooManyRequests(retry_after)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(value: &str) -> (u32, u64) {
+        let limit = value.parse::<Limit>().unwrap();
+        (limit.requests, limit.window.as_secs())
+    }
+
+    #[test]
+    fn parses_limits() {
+        assert_eq!(limit("100/1m"), (100, 60));
+        assert_eq!(limit("5/10s"), (5, 10));
+        assert_eq!(limit("10/s"), (10, 1));
+        assert_eq!(limit(" 3 / 2h "), (3, 7200));
+        assert_eq!(limit("1000/24h"), (1000, 86_400));
+    }
+
+    #[test]
+    fn rejects_invalid_limits() {
+        for value in [
+            "", "100", "100/", "/1m", "0/1m", "10/0s", "10/1d", "-1/1m", "1.5/1m",
+        ] {
+            assert!(value.parse::<Limit>().is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn rejects_windows_over_a_day() {
+        assert!("1/99999999999999999h".parse::<Limit>().is_err());
+        assert!("1/3000000000000000h".parse::<Limit>().is_err());
+        assert!("1/25h".parse::<Limit>().is_err());
+        assert!("1/86401s".parse::<Limit>().is_err());
+        assert!("99999999999/1s".parse::<Limit>().is_err());
+    }
+
+    #[test]
+    fn refills_at_the_rate_of_the_limit() {
+        assert_eq!("10/s".parse::<Limit>().unwrap().rate(), 10.0);
+        assert_eq!("60/1m".parse::<Limit>().unwrap().rate(), 1.0);
+    }
+}
@@ -0,0 +1,571 @@
+use salvo::prelude::*;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::error::{AppError, AppResult};
+use crate::storage::{item_id, Storage};
+use crate::trash::{is_deleted, DELETED_AT};
+
+/// A declared reference from items of `collection` to items of `references`,
+/// e.g. `comments.postId -> posts.id`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Relationship {
+    pub collection: String,
+    /// Defaults to the conventional foreign key, e.g. `postId` for `posts`.
+    field: Option<String>,
+    pub references: String,
+    #[serde(default)]
+    pub on_delete: OnDelete,
+}
+
+impl Relationship {
+    pub fn field(&self) -> String {
+        self.field
+            .clone()
+            .unwrap_or_else(|| foreign_key(&self.references))
+    }
+}
+
+/// What deleting a referenced item does to the items pointing at it.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OnDelete {
+    /// Leave dependent items untouched.
+    #[default]
+    NoAction,
+    /// Delete dependent items too.
+    Cascade,
+    /// Refuse the delete with 409 while dependent items exist.
+    Restrict,
+    /// Set the reference of dependent items to `null`.
+    SetNull,
+}
+
+/// Related collections asked for with `?_embed=` (children) and `?_expand=`
+/// (parents). Both accept a comma separated list and may be repeated.
+///
+/// Relationships follow naming conventions: a `comments` item points at its
+/// post through `postId`, so `posts?_embed=comments` attaches each post's
+/// comments and `comments?_expand=post` attaches each comment's post.
+/// Both honour the field of a declared [`Relationship`] when there is one:
+/// with `posts.authorId -> users`, `posts?_expand=author` (or `user`)
+/// attaches each post's user.
+#[derive(Debug, Default)]
+pub struct Relations {
+    embed: Vec<String>,
+    expand: Vec<String>,
+}
+
+impl Relations {
+    pub fn from_request(req: &Request) -> Self {
+        let names = |key: &str| -> Vec<String> {
+            req.queries()
+                .get_vec(key)
+                .into_iter()
+                .flatten()
+                .flat_map(|value| value.split(','))
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+
+        Self {
+            embed: names("_embed"),
+            expand: names("_expand"),
+        }
+    }
+
+    /// The related collections asked for on items of `f`.
+    pub fn collections<'a>(
+        &'a self,
+        relationships: &'a [Relationship],
+        f: &'a str,
+    ) -> impl Iterator<Item = String> + 'a {
+        self.embed.iter().cloned().chain(
+            self.expand
+                .iter()
+                .map(move |parent| parent_key(relationships, f, parent).0),
+        )
+    }
+
+    /// Attaches the related items to `items` of `f`, once `retain` has
+    /// dropped those the client may not see from each related collection.
+    pub async fn apply(
+        &self,
+        storage: &dyn Storage,
+        relationships: &[Relationship],
+        f: &str,
+        items: &mut [serde_json::Value],
+        retain: impl Fn(&str, &mut Vec<serde_json::Value>),
+    ) -> AppResult<()> {
+        for child in &self.embed {
+            let mut children = existing_items(storage, child).await?;
+            retain(child, &mut children);
+            let key = child_key(relationships, f, child);
+            for item in items.iter_mut() {
+                let Some(id) = item_id(item) else {
+                    continue;
+                };
+                item[child.as_str()] = children
+                    .iter()
+                    .filter(|child| references(&child[key.as_str()], id))
+                    .cloned()
+                    .collect();
+            }
+        }
+
+        for parent in &self.expand {
+            let (parent_collection, key) = parent_key(relationships, f, parent);
+            let mut parents = existing_items(storage, &parent_collection).await?;
+            retain(&parent_collection, &mut parents);
+            for item in items.iter_mut() {
+                let found = parents
+                    .iter()
+                    .find(|candidate| {
+                        item_id(candidate).is_some_and(|id| references(&item[key.as_str()], id))
+                    })
+                    .cloned();
+                if let Some(found) = found {
+                    item[parent.as_str()] = found;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Items of `child` that belong to item `id` of collection `f`.
+pub async fn children_of(
+    storage: &dyn Storage,
+    relationships: &[Relationship],
+    f: &str,
+    id: u64,
+    child: &str,
+) -> AppResult<Vec<serde_json::Value>> {
+    let key = child_key(relationships, f, child);
+    Ok(existing_items(storage, child)
+        .await?
+        .into_iter()
+        .filter(|item| references(&item[key.as_str()], id))
+        .collect())
+}
+
+/// Deletes item `id` of `f`, applying the `on_delete` action of every
+/// relationship that references it (recursively, for cascades).
+///
+/// Nothing is changed when a restricted reference is found anywhere along
+/// the way.
+///
+/// With `expected`, the item itself is deleted first, and only if it is
+/// still `expected`, so that nothing changes when it is not.
+///
+/// With `deleted_at`, items are soft-deleted instead: they (and cascaded
+/// dependents) get `deletedAt` set, items already in the trash are ignored,
+/// and `set_null` references are kept so that a restore brings them back.
+pub async fn delete_with_references(
+    storage: &dyn Storage,
+    relationships: &[Relationship],
+    f: &str,
+    id: u64,
+    expected: Option<&serde_json::Value>,
+    deleted_at: Option<&serde_json::Value>,
+) -> AppResult<bool> {
+    if storage.get(f, id).await.is_err() {
+        return Ok(false);
+    }
+
+    let mut collections: HashMap<String, Vec<serde_json::Value>> = HashMap::new();
+    let mut deletes = vec![(f.to_string(), id)];
+    let mut set_nulls = Vec::new();
+    let mut visited = HashSet::from([(f.to_string(), id)]);
+    let mut pending = VecDeque::from([(f.to_string(), id)]);
+
+    while let Some((parent, parent_id)) = pending.pop_front() {
+        for relationship in relationships.iter().filter(|r| r.references == parent) {
+            if relationship.on_delete == OnDelete::NoAction {
+                continue;
+            }
+
+            let child = &relationship.collection;
+            if !collections.contains_key(child) {
+                collections.insert(child.clone(), existing_items(storage, child).await?);
+            }
+            let field = relationship.field();
+            let dependents = collections[child]
+                .iter()
+                .filter(|item| references(&item[field.as_str()], parent_id))
+                .filter(|item| deleted_at.is_none() || !is_deleted(item))
+                .filter_map(item_id);
+
+            for dependent in dependents {
+                match relationship.on_delete {
+                    OnDelete::Restrict => {
+                        return Err(AppError::Conflict(format!(
+                            "{parent}/{parent_id} is still referenced by {child}/{dependent}"
+                        )));
+                    }
+                    OnDelete::Cascade => {
+                        if visited.insert((child.clone(), dependent)) {
+                            deletes.push((child.clone(), dependent));
+                            pending.push_back((child.clone(), dependent));
+                        }
+                    }
+                    OnDelete::SetNull => set_nulls.push((child.clone(), dependent, field.clone())),
+                    OnDelete::NoAction => {}
+                }
+            }
+        }
+    }
+
+    if let Some(deleted_at) = deleted_at {
+        let mark = serde_json::json!({ DELETED_AT: deleted_at });
+        if storage.patch_if(f, id, expected, &mark).await?.is_none() {
+            return Ok(false);
+        }
+        for (collection, item) in &deletes[1..] {
+            storage.patch(collection, *item, &mark).await?;
+        }
+        return Ok(true);
+    }
+
+    if expected.is_some() && !storage.delete_if(f, id, expected).await? {
+        return Ok(false);
+    }
+
+    for (child, dependent, field) in set_nulls {
+        if visited.contains(&(child.clone(), dependent)) {
+            continue;
+        }
+        if let Ok(mut item) = storage.get(&child, dependent).await {
+            item[field.as_str()] = serde_json::Value::Null;
+            storage.replace(&child, dependent, &item).await?;
+        }
+    }
+    // The item itself, first in `deletes`, is already gone when conditional.
+    let skipped = usize::from(expected.is_some());
+    for (collection, item) in deletes[skipped..].iter().rev() {
+        storage.delete(collection, *item).await?;
+    }
+
+    Ok(true)
+}
+
+/// Brings soft-deleted item `id` of `f` back, along with the dependents its
+/// deletion cascaded to (those deleted at the very same time).
+///
+/// Returns the restored item, or `None` when it does not exist.
+pub async fn restore_with_references(
+    storage: &dyn Storage,
+    relationships: &[Relationship],
+    f: &str,
+    id: u64,
+) -> AppResult<Option<serde_json::Value>> {
+    let Ok(item) = storage.get(f, id).await else {
+        return Ok(None);
+    };
+    let deleted_at = item[DELETED_AT].clone();
+    if deleted_at.is_null() {
+        return Ok(Some(item));
+    }
+
+    let mut restores = vec![(f.to_string(), id)];
+    let mut visited = HashSet::from([(f.to_string(), id)]);
+    let mut pending = VecDeque::from([(f.to_string(), id)]);
+
+    while let Some((parent, parent_id)) = pending.pop_front() {
+        let cascades = relationships
+            .iter()
+            .filter(|r| r.references == parent && r.on_delete == OnDelete::Cascade);
+        for relationship in cascades {
+            let child = &relationship.collection;
+            let field = relationship.field();
+            let dependents: Vec<u64> = existing_items(storage, child)
+                .await?
+                .iter()
+                .filter(|item| references(&item[field.as_str()], parent_id))
+                .filter(|item| item[DELETED_AT] == deleted_at)
+                .filter_map(item_id)
+                .collect();
+
+            for dependent in dependents {
+                if visited.insert((child.clone(), dependent)) {
+                    restores.push((child.clone(), dependent));
+                    pending.push_back((child.clone(), dependent));
+                }
+            }
+        }
+    }
+
+    let unmark = serde_json::json!({ DELETED_AT: null });
+    let mut restored = None;
+    for (collection, item) in &restores {
+        let patched = storage.patch(collection, *item, &unmark).await?;
+        restored = restored.or(patched);
+    }
+    Ok(restored)
+}
+
+/// Field through which items of `child` point at items of `f`.
+fn child_key(relationships: &[Relationship], f: &str, child: &str) -> String {
+    relationships
+        .iter()
+        .find(|r| r.collection == child && r.references == f)
+        .map_or_else(|| foreign_key(f), Relationship::field)
+}
+
+/// Collection of the `parent` of items of `f` asked for with `_expand`, and
+/// the field through which they point at it: those of a relationship
+/// declared with the field `{parent}Id`, or else referencing the collection
+/// named after `parent`, or else the conventional ones.
+fn parent_key(relationships: &[Relationship], f: &str, parent: &str) -> (String, String) {
+    let key = format!("{parent}Id");
+    let collection = plural(parent);
+    let declared = relationships.iter().filter(|r| r.collection == f);
+    declared
+        .clone()
+        .find(|r| r.field() == key)
+        .or_else(|| declared.clone().find(|r| r.references == collection))
+        .map_or((collection, key), |r| (r.references.clone(), r.field()))
+}
+
+/// Reads a related collection without creating it when it does not exist.
+async fn existing_items(storage: &dyn Storage, f: &str) -> AppResult<Vec<serde_json::Value>> {
+    if storage.list_collections().await?.iter().any(|c| c == f) {
+        storage.get_all(f).await
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+/// Field through which items of other collections point at collection `f`,
+/// e.g. `postId` for `posts`.
+pub fn foreign_key(f: &str) -> String {
+    format!("{}Id", singular(f))
+}
+
+fn references(value: &serde_json::Value, id: u64) -> bool {
+    value.as_u64() == Some(id) || value.as_str().and_then(|s| s.parse().ok()) == Some(id)
+}
+
+fn singular(f: &str) -> String {
+    if let Some(stem) = f.strip_suffix("ies") {
+        format!("{stem}y")
+    } else if let Some(stem) = f.strip_suffix('s') {
+        stem.to_string()
+    } else {
+        f.to_string()
+    }
+}
+
+fn plural(name: &str) -> String {
+    match name.strip_suffix('y') {
+        Some(stem) if !stem.ends_with(['a', 'e', 'i', 'o', 'u']) => format!("{stem}ies"),
+        _ => format!("{name}s"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use serde_json::json;
+
+    fn relationships(declared: serde_json::Value) -> Vec<Relationship> {
+        serde_json::from_value(declared).unwrap()
+    }
+
+    async fn expand(relationships: &[Relationship], parent: &str) -> serde_json::Value {
+        let storage = MemoryStorage::default();
+        for user in [
+            json!({ "id": 1, "name": "a" }),
+            json!({ "id": 2, "name": "b" }),
+        ] {
+            storage.insert("users", user).await.unwrap();
+        }
+        let relations = Relations {
+            expand: vec![parent.to_string()],
+            ..Relations::default()
+        };
+        let mut items = [json!({ "id": 7, "authorId": 1, "userId": 2 })];
+        relations
+            .apply(&storage, relationships, "posts", &mut items, |_, _| {})
+            .await
+            .unwrap();
+        items[0][parent].clone()
+    }
+
+    #[tokio::test]
+    async fn expands_declared_relationships() {
+        let declared = relationships(json!([
+            { "collection": "posts", "field": "authorId", "references": "users" }
+        ]));
+        let user = json!({ "id": 1, "name": "a" });
+        assert_eq!(expand(&declared, "author").await, user);
+        assert_eq!(expand(&declared, "user").await, user);
+
+        let relations = Relations {
+            expand: vec!["author".to_string()],
+            ..Relations::default()
+        };
+        assert_eq!(
+            relations
+                .collections(&declared, "posts")
+                .collect::<Vec<_>>(),
+            ["users"]
+        );
+    }
+
+    #[tokio::test]
+    async fn expands_conventional_relationships() {
+        assert_eq!(expand(&[], "user").await, json!({ "id": 2, "name": "b" }));
+        assert_eq!(expand(&[], "author").await, serde_json::Value::Null);
+    }
+
+    async fn seeded(collections: serde_json::Value) -> MemoryStorage {
+        let storage = MemoryStorage::default();
+        for (f, items) in collections.as_object().unwrap() {
+            storage
+                .replace_collection(f, items.as_array().unwrap().clone())
+                .await
+                .unwrap();
+        }
+        storage
+    }
+
+    async fn ids(storage: &MemoryStorage, f: &str) -> Vec<u64> {
+        let items = storage.get_all(f).await.unwrap();
+        items.iter().filter_map(item_id).collect()
+    }
+
+    #[tokio::test]
+    async fn restrict_leaves_everything_in_place() {
+        let declared = relationships(json!([
+            { "collection": "comments", "references": "posts", "on_delete": "cascade" },
+            { "collection": "likes", "references": "comments", "on_delete": "restrict" }
+        ]));
+        let storage = seeded(json!({
+            "posts": [{ "id": 1 }],
+            "comments": [{ "id": 2, "postId": 1 }],
+            "likes": [{ "id": 3, "commentId": 2 }]
+        }))
+        .await;
+
+        let deleted = delete_with_references(&storage, &declared, "posts", 1, None, None).await;
+        assert!(matches!(deleted, Err(AppError::Conflict(_))));
+        assert_eq!(ids(&storage, "posts").await, [1]);
+        assert_eq!(ids(&storage, "comments").await, [2]);
+        assert_eq!(ids(&storage, "likes").await, [3]);
+    }
+
+    #[tokio::test]
+    async fn cascades_through_every_level() {
+        let declared = relationships(json!([
+            { "collection": "comments", "references": "posts", "on_delete": "cascade" },
+            { "collection": "likes", "references": "comments", "on_delete": "cascade" }
+        ]));
+        let storage = seeded(json!({
+            "posts": [{ "id": 1 }, { "id": 2 }],
+            "comments": [{ "id": 3, "postId": 1 }, { "id": 4, "postId": 2 }],
+            "likes": [{ "id": 5, "commentId": 3 }, { "id": 6, "commentId": 4 }]
+        }))
+        .await;
+
+        assert!(
+            delete_with_references(&storage, &declared, "posts", 1, None, None)
+                .await
+                .unwrap()
+        );
+        assert_eq!(ids(&storage, "posts").await, [2]);
+        assert_eq!(ids(&storage, "comments").await, [4]);
+        assert_eq!(ids(&storage, "likes").await, [6]);
+    }
+
+    #[tokio::test]
+    async fn set_null_skips_items_deleted_too() {
+        let declared = relationships(json!([
+            { "collection": "comments", "references": "posts", "on_delete": "cascade" },
+            { "collection": "comments", "field": "quotedPostId", "references": "posts", "on_delete": "set_null" }
+        ]));
+        let storage = seeded(json!({
+            "posts": [{ "id": 1 }],
+            "comments": [
+                { "id": 2, "postId": 1, "quotedPostId": 1 },
+                { "id": 3, "postId": 9, "quotedPostId": 1 }
+            ]
+        }))
+        .await;
+
+        assert!(
+            delete_with_references(&storage, &declared, "posts", 1, None, None)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            storage.get_all("comments").await.unwrap(),
+            [json!({ "id": 3, "postId": 9, "quotedPostId": null })]
+        );
+    }
+
+    #[tokio::test]
+    async fn restores_what_a_soft_delete_cascaded_to() {
+        let declared = relationships(json!([
+            { "collection": "comments", "references": "posts", "on_delete": "cascade" },
+            { "collection": "likes", "references": "comments", "on_delete": "cascade" },
+            { "collection": "drafts", "references": "posts", "on_delete": "set_null" }
+        ]));
+        let storage = seeded(json!({
+            "posts": [{ "id": 1 }],
+            "comments": [
+                { "id": 2, "postId": 1 },
+                { "id": 3, "postId": 1, "deletedAt": "earlier" }
+            ],
+            "likes": [{ "id": 4, "commentId": 2 }],
+            "drafts": [{ "id": 5, "postId": 1 }]
+        }))
+        .await;
+
+        let now = json!("now");
+        assert!(
+            delete_with_references(&storage, &declared, "posts", 1, None, Some(&now))
+                .await
+                .unwrap()
+        );
+        for (f, id) in [("posts", 1), ("comments", 2), ("likes", 4)] {
+            assert_eq!(storage.get(f, id).await.unwrap()[DELETED_AT], now);
+        }
+        assert_eq!(
+            storage.get("comments", 3).await.unwrap()[DELETED_AT],
+            "earlier"
+        );
+        assert_eq!(
+            storage.get("drafts", 5).await.unwrap(),
+            json!({ "id": 5, "postId": 1 })
+        );
+
+        let restored = restore_with_references(&storage, &declared, "posts", 1).await;
+        assert_eq!(restored.unwrap(), Some(json!({ "id": 1 })));
+        for (f, id) in [("posts", 1), ("comments", 2), ("likes", 4)] {
+            assert!(!is_deleted(&storage.get(f, id).await.unwrap()));
+        }
+        assert!(is_deleted(&storage.get("comments", 3).await.unwrap()));
+    }
+
+    #[tokio::test]
+    async fn missing_items_are_not_deleted_or_restored() {
+        let storage = seeded(json!({ "posts": [] })).await;
+        assert!(
+            !delete_with_references(&storage, &[], "posts", 1, None, None)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            restore_with_references(&storage, &[], "posts", 1)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,264 @@
+use salvo::http::uri::{PathAndQuery, Uri};
+use salvo::prelude::*;
+use salvo::routing::PathState;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::error::{AppError, AppResult};
+
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    /// `:name`, matching any segment.
+    Param(String),
+    /// `*`, matching the rest of the path.
+    Rest,
+}
+
+/// A custom route: requests whose path matches `pattern` are handled as if
+/// made to `target`, with the `:name` and `*` of the pattern replaced with
+/// what they matched.
+#[derive(Debug)]
+struct Route {
+    pattern: Vec<Segment>,
+    target: String,
+}
+
+/// Whether `c` can be part of the name of a `:name` parameter.
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Segments of `path`, without leading and trailing slashes.
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.trim_matches('/')
+        .split('/')
+        .filter(|part| !part.is_empty())
+}
+
+impl Route {
+    fn parse(pattern: &str, target: &str) -> AppResult<Self> {
+        let invalid = |message: &str| {
+            AppError::BadRequest(format!(
+                "invalid route `{pattern}` -> `{target}`: {message}"
+            ))
+        };
+        if !pattern.starts_with('/') || !target.starts_with('/') {
+            return Err(invalid("paths must start with `/`"));
+        }
+
+        let mut segments = segments(pattern).peekable();
+        let mut parsed = Vec::new();
+        while let Some(part) = segments.next() {
+            parsed.push(match part {
+                "*" if segments.peek().is_none() => Segment::Rest,
+                "*" => return Err(invalid("`*` can only end a path")),
+                _ => match part.strip_prefix(':') {
+                    Some(name) if !name.is_empty() && name.chars().all(is_name_char) => {
+                        Segment::Param(name.to_string())
+                    }
+                    Some(_) => return Err(invalid("invalid parameter name")),
+                    None => Segment::Literal(part.to_string()),
+                },
+            });
+        }
+        let route = Self {
+            pattern: parsed,
+            target: target.to_string(),
+        };
+
+        // Every placeholder of the target must be filled in.
+        let mut unknown = None;
+        route.substitute(|placeholder| {
+            let known = route.pattern.iter().any(|segment| match segment {
+                Segment::Param(name) => name == placeholder,
+                Segment::Rest => placeholder == "*",
+                Segment::Literal(_) => false,
+            });
+            if !known && unknown.is_none() {
+                unknown = Some(placeholder.to_string());
+            }
+            String::new()
+        });
+        match unknown {
+            Some(placeholder) => Err(invalid(&format!(
+                "`{placeholder}` is not part of the pattern"
+            ))),
+            None => Ok(route),
+        }
+    }
+
+    /// What the parameters of the pattern match in `path` (`*` for the rest
+    /// of the path), if it matches.
+    fn matches(&self, path: &str) -> Option<HashMap<&str, String>> {
+        let mut parts = segments(path);
+        let mut params = HashMap::new();
+        for segment in &self.pattern {
+            match segment {
+                Segment::Literal(literal) => {
+                    if parts.next()? != literal {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    params.insert(name.as_str(), parts.next()?.to_string());
+                }
+                Segment::Rest => {
+                    params.insert("*", parts.by_ref().collect::<Vec<_>>().join("/"));
+                }
+            }
+        }
+        parts.next().is_none().then_some(params)
+    }
+
+    /// The target with each placeholder replaced by `value` of its name.
+    fn substitute(&self, mut value: impl FnMut(&str) -> String) -> String {
+        let mut result = String::with_capacity(self.target.len());
+        let mut rest = self.target.as_str();
+        while let Some(at) = rest.find([':', '*']) {
+            result.push_str(&rest[..at]);
+            if rest[at..].starts_with('*') {
+                result.push_str(&value("*"));
+                rest = &rest[at + 1..];
+                continue;
+            }
+            let name_len = rest[at + 1..]
+                .find(|c| !is_name_char(c))
+                .unwrap_or(rest.len() - at - 1);
+            if name_len == 0 {
+                result.push(':');
+            } else {
+                result.push_str(&value(&rest[at + 1..at + 1 + name_len]));
+            }
+            rest = &rest[at + 1 + name_len..];
+        }
+        result.push_str(rest);
+        result
+    }
+
+    /// How many literal segments the pattern has, the most specific routes
+    /// being tried first, in the order of their patterns otherwise.
+    fn specificity(&self) -> usize {
+        self.pattern
+            .iter()
+            .filter(|segment| matches!(segment, Segment::Literal(_)))
+            .count()
+    }
+}
+
+/// Custom routes read from the file given with `--routes`, mapping paths to
+/// the routes of the API:
+///
+/// ```json
+/// { "/v1/blog/:slug": "/api/posts?slug=:slug", "/me": "/api/users/1" }
+/// ```
+#[derive(Debug, Default)]
+pub struct Routes(Vec<Route>);
+
+impl Routes {
+    pub fn load(path: &str) -> AppResult<Self> {
+        let json_string = std::fs::read_to_string(path)?;
+        let routes: BTreeMap<String, String> = serde_json::from_str(&json_string)?;
+
+        let mut routes = routes
+            .iter()
+            .map(|(pattern, target)| Route::parse(pattern, target))
+            .collect::<AppResult<Vec<_>>>()?;
+        routes.sort_by_key(|route| std::cmp::Reverse(route.specificity()));
+        Ok(Self(routes))
+    }
+
+    /// Points `req` to the target of the first route matching its path, if
+    /// any, for the router to find its handler. The query of the request is
+    /// kept, after that of the target.
+    pub fn rewrite(&self, req: &mut Request, path_state: &mut PathState) {
+        let path = req.uri().path();
+        let Some((route, params)) = self
+            .0
+            .iter()
+            .find_map(|route| Some((route, route.matches(path)?)))
+        else {
+            return;
+        };
+
+        let mut target = route.substitute(|name| params[name].clone());
+        if let Some(query) = req.uri().query().filter(|query| !query.is_empty()) {
+            target.push(if target.contains('?') { '&' } else { '?' });
+            target.push_str(query);
+        }
+        let mut parts = req.uri().clone().into_parts();
+        let Ok(path_and_query) = PathAndQuery::try_from(target) else {
+            return;
+        };
+        parts.path_and_query = Some(path_and_query);
+        let Ok(uri) = Uri::from_parts(parts) else {
+            return;
+        };
+
+        *path_state = PathState::new(uri.path());
+        req.set_uri(uri);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(pattern: &str, target: &str) -> Route {
+        Route::parse(pattern, target).unwrap()
+    }
+
+    #[test]
+    fn parse_reads_literals_params_and_rest() {
+        let route = route("/v1/:kind/*", "/api/:kind/*");
+        assert!(matches!(
+            route.pattern.as_slice(),
+            [Segment::Literal(v1), Segment::Param(kind), Segment::Rest]
+                if v1 == "v1" && kind == "kind"
+        ));
+        assert_eq!(route.specificity(), 1);
+    }
+
+    #[test]
+    fn parse_rejects_invalid_routes() {
+        assert!(Route::parse("v1/posts", "/api/posts").is_err());
+        assert!(Route::parse("/v1/posts", "api/posts").is_err());
+        assert!(Route::parse("/v1/*/posts", "/api/posts").is_err());
+        assert!(Route::parse("/v1/:", "/api/posts").is_err());
+        assert!(Route::parse("/v1/:a-b", "/api/posts").is_err());
+        assert!(Route::parse("/v1/blog/:slug", "/api/posts/:id").is_err());
+        assert!(Route::parse("/v1/blog", "/api/*").is_err());
+    }
+
+    #[test]
+    fn matches_captures_params_and_rest() {
+        let blog = route("/v1/blog/:slug", "/api/posts?slug=:slug");
+        let params = blog.matches("/v1/blog/hello/").unwrap();
+        assert_eq!(params["slug"], "hello");
+        assert!(blog.matches("/v1/blog").is_none());
+        assert!(blog.matches("/v1/blog/hello/comments").is_none());
+        assert!(blog.matches("/v2/blog/hello").is_none());
+
+        let rest = route("/v1/*", "/api/*");
+        assert_eq!(
+            rest.matches("/v1/posts/1/comments").unwrap()["*"],
+            "posts/1/comments"
+        );
+        assert_eq!(rest.matches("/v1").unwrap()["*"], "");
+    }
+
+    #[test]
+    fn substitute_fills_in_path_and_query() {
+        let route = route("/v1/:kind/:page", "/api/:kind?page=:page&per_page=10");
+        let params = route.matches("/v1/posts/2").unwrap();
+        assert_eq!(
+            route.substitute(|name| params[name].clone()),
+            "/api/posts?page=2&per_page=10"
+        );
+        assert_eq!(
+            Route::parse("/v1/*", "/api/*?sep=:&x=1")
+                .unwrap()
+                .substitute(|name| format!("<{name}>")),
+            "/api/<*>?sep=:&x=1"
+        );
+    }
+}
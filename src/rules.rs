@@ -0,0 +1,102 @@
+use salvo::http::Method;
+use salvo::prelude::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::auth::{caller, AuthConfig};
+use crate::error::{AppError, AppResult};
+use crate::ops;
+use crate::relations::Relations;
+use crate::AppConfig;
+
+/// Keys of the rules of a collection: kinds of methods, or methods, which
+/// take precedence.
+const KEYS: [&str; 7] = ["read", "write", "GET", "POST", "PUT", "PATCH", "DELETE"];
+
+/// Who may make requests of a given method on a collection.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    Public,
+    /// Clients with an API key, a bearer token or a JWT.
+    Authenticated,
+    /// Signed-in users, on their own items only.
+    Owner,
+    Denied,
+}
+
+/// Access rules read from the file given with `--rules`: for each collection
+/// (`*` for the others), the access given by method or kind of method.
+///
+/// ```json
+/// { "users": { "read": "public", "write": "denied" }, "*": { "DELETE": "authenticated" } }
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Rules(HashMap<String, HashMap<String, Access>>);
+
+impl Rules {
+    /// Reads the rules of `path`; `owner` rules are only allowed on the
+    /// collections whose items have owners in `auth`.
+    pub fn load(path: &str, auth: &AuthConfig) -> AppResult<Self> {
+        let json_string = std::fs::read_to_string(path)?;
+        let rules: Self = serde_json::from_str(&json_string)?;
+
+        for (f, methods) in &rules.0 {
+            if let Some(key) = methods.keys().find(|key| !KEYS.contains(&key.as_str())) {
+                return Err(AppError::BadRequest(format!(
+                    "unknown method `{key}` in the rules of `{f}`: expected one of {}",
+                    KEYS.join(", ")
+                )));
+            }
+            let owned = auth
+                .jwt
+                .as_ref()
+                .is_some_and(|jwt| jwt.owners.contains_key(f));
+            if methods.values().any(|access| *access == Access::Owner) && !owned {
+                return Err(AppError::BadRequest(format!(
+                    "`{f}` has an `owner` rule but no owner field in `auth.jwt.owners`"
+                )));
+            }
+        }
+        Ok(rules)
+    }
+
+    /// The access to `f` (`*` for the default one) with `method` set by the
+    /// rules, if any.
+    pub fn access(&self, f: &str, method: &Method) -> Option<Access> {
+        let methods = self.0.get(f)?;
+        let kind = if matches!(*method, Method::GET | Method::HEAD) {
+            "read"
+        } else {
+            "write"
+        };
+        methods
+            .get(method.as_str())
+            .or_else(|| methods.get(kind))
+            .copied()
+    }
+}
+
+/// Rejects requests the access rules do not allow on the collections they
+/// touch.
+#[handler]
+pub async fn enforce(req: &mut Request, depot: &mut Depot) -> AppResult<()> {
+    let app_config = depot.obtain::<AppConfig>().unwrap();
+    let caller = caller(depot);
+
+    let f = req.param::<String>("f");
+    if let Some(f) = &f {
+        ops::check_access(app_config, f, req.method(), &caller)?;
+    }
+    // Nested routes, `_embed` and `_expand` read other collections too.
+    let child = req.param::<String>("child");
+    if let Some(child) = &child {
+        ops::check_access(app_config, child, &Method::GET, &caller)?;
+    }
+    let listed = child.or(f).unwrap_or_default();
+    let relations = Relations::from_request(req);
+    for related in relations.collections(&app_config.relationships, &listed) {
+        ops::check_access(app_config, &related, &Method::GET, &caller)?;
+    }
+    Ok(())
+}
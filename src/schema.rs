@@ -0,0 +1,62 @@
+use serde::Serialize;
+
+use crate::error::{AppError, AppResult};
+
+/// `{f}.schema.json` holds the JSON Schema items of collection `f` must match.
+pub const SCHEMA_FILE_SUFFIX: &str = ".schema.json";
+
+/// One reason an item was rejected by its collection schema.
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    /// JSON pointer to the offending value, `""` for the item itself.
+    pub field: String,
+    pub message: String,
+}
+
+/// Looks up collection schemas in a directory. Schema files are read on
+/// every write, so they can be edited while the server runs.
+#[derive(Clone, Debug, Default)]
+pub struct Schemas {
+    dir: Option<String>,
+}
+
+impl Schemas {
+    pub fn new(dir: Option<String>) -> Self {
+        Self { dir }
+    }
+
+    async fn load(&self, f: &str) -> AppResult<Option<serde_json::Value>> {
+        let Some(dir) = &self.dir else {
+            return Ok(None);
+        };
+        match tokio::fs::read_to_string(format!("{dir}/{f}{SCHEMA_FILE_SUFFIX}")).await {
+            Ok(json_string) => serde_json::from_str(&json_string)
+                .map(Some)
+                .map_err(|err| AppError::Schema(format!("{f}{SCHEMA_FILE_SUFFIX}: {err}"))),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Checks `item` against the schema of collection `f`, if it has one.
+    pub async fn validate(&self, f: &str, item: &serde_json::Value) -> AppResult<()> {
+        let Some(schema) = self.load(f).await? else {
+            return Ok(());
+        };
+        let validator = jsonschema::validator_for(&schema)
+            .map_err(|err| AppError::Schema(format!("{f}{SCHEMA_FILE_SUFFIX}: {err}")))?;
+
+        let errors: Vec<FieldError> = validator
+            .iter_errors(item)
+            .map(|err| FieldError {
+                field: err.instance_path().to_string(),
+                message: err.to_string(),
+            })
+            .collect();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(AppError::Validation(errors))
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use salvo::async_trait;
+use std::fmt::Debug;
+use std::io;
+use std::path::Path;
+
+use crate::error::{AppError, AppResult};
+use crate::schema::SCHEMA_FILE_SUFFIX;
+use crate::utils::{generate_random_id, merge_patch};
+
+mod db_file;
+mod json_file;
+mod json_lines;
+mod memory;
+mod sqlite;
+
+pub use db_file::DbFileStorage;
+pub use json_file::JsonFileStorage;
+pub use memory::MemoryStorage;
+pub use sqlite::{SqliteStorage, SQLITE_FILE};
+
+/// Persistence backend for the collections served under `/api/{f}`.
+///
+/// A collection that does not exist yet behaves like an empty one: reading it
+/// creates it, while updates and deletes report that nothing was found.
+#[async_trait]
+pub trait Storage: Debug + Send + Sync {
+    /// Where the collections live on disk, if anywhere.
+    fn location(&self) -> Option<&str>;
+
+    async fn list_collections(&self) -> AppResult<Vec<String>>;
+
+    async fn get_all(&self, f: &str) -> AppResult<Vec<serde_json::Value>>;
+
+    async fn get(&self, f: &str, id: u64) -> AppResult<serde_json::Value>;
+
+    async fn insert(&self, f: &str, new_item: serde_json::Value) -> AppResult<serde_json::Value>;
+
+    async fn replace(&self, f: &str, id: u64, updated_item: &serde_json::Value) -> AppResult<bool> {
+        self.replace_if(f, id, None, updated_item).await
+    }
+
+    /// Applies `patch` as a JSON merge patch and returns the merged item.
+    async fn patch(
+        &self,
+        f: &str,
+        id: u64,
+        patch: &serde_json::Value,
+    ) -> AppResult<Option<serde_json::Value>> {
+        self.patch_if(f, id, None, patch).await
+    }
+
+    async fn delete(&self, f: &str, id: u64) -> AppResult<bool> {
+        self.delete_if(f, id, None).await
+    }
+
+    /// Like `replace`, but when `expected` is given, only if item `id` is
+    /// still `expected`, checked under the same lock as the write. Fails
+    /// with `412 Precondition Failed` otherwise.
+    async fn replace_if(
+        &self,
+        f: &str,
+        id: u64,
+        expected: Option<&serde_json::Value>,
+        updated_item: &serde_json::Value,
+    ) -> AppResult<bool>;
+
+    /// Like `patch`, but only if item `id` is still `expected` when given.
+    async fn patch_if(
+        &self,
+        f: &str,
+        id: u64,
+        expected: Option<&serde_json::Value>,
+        patch: &serde_json::Value,
+    ) -> AppResult<Option<serde_json::Value>>;
+
+    /// Like `delete`, but only if item `id` is still `expected` when given.
+    async fn delete_if(
+        &self,
+        f: &str,
+        id: u64,
+        expected: Option<&serde_json::Value>,
+    ) -> AppResult<bool>;
+
+    async fn count(&self, f: &str) -> AppResult<usize> {
+        Ok(self.get_all(f).await?.len())
+    }
+
+    /// Overwrites the whole collection, creating it if needed.
+    async fn replace_collection(&self, f: &str, items: Vec<serde_json::Value>) -> AppResult<()>;
+
+    async fn delete_collection(&self, f: &str) -> AppResult<()>;
+}
+
+/// Copies every collection of `from` into `to`, replacing what was there.
+pub async fn copy_collections(from: &dyn Storage, to: &dyn Storage) -> AppResult<Vec<String>> {
+    let collections = from.list_collections().await?;
+    for f in &collections {
+        to.replace_collection(f, from.get_all(f).await?).await?;
+    }
+    Ok(collections)
+}
+
+pub fn item_id(item: &serde_json::Value) -> Option<u64> {
+    item["id"].as_u64()
+}
+
+/// Whether a data directory entry holds a collection schema rather than data.
+fn is_schema_file(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().ends_with(SCHEMA_FILE_SUFFIX))
+}
+
+fn into_items(f: &str, json_value: serde_json::Value) -> AppResult<Vec<serde_json::Value>> {
+    match json_value {
+        serde_json::Value::Array(items) => Ok(items),
+        _ => Err(AppError::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("collection `{f}` is not a JSON array"),
+        ))),
+    }
+}
+
+fn find_item(items: &[serde_json::Value], id: u64) -> AppResult<serde_json::Value> {
+    items
+        .iter()
+        .find(|item| item_id(item) == Some(id))
+        .cloned()
+        .ok_or(AppError::ItemNotFound(id))
+}
+
+/// How many random ids are tried before giving up on finding a free one.
+const MAX_ID_ATTEMPTS: usize = 1_000;
+
+/// Gives `new_item` a random id for which `taken` is false, unless it has an
+/// id already, which `taken` must then be false for.
+fn assign_id(
+    new_item: &mut serde_json::Value,
+    mut taken: impl FnMut(u64) -> AppResult<bool>,
+) -> AppResult<()> {
+    let Some(fields) = new_item.as_object_mut() else {
+        return Err(AppError::BadRequest(
+            "items must be JSON objects".to_string(),
+        ));
+    };
+    if let Some(id) = fields.get("id") {
+        return match id.as_u64() {
+            Some(id) if taken(id)? => Err(AppError::Conflict(format!(
+                "an item with id {id} already exists"
+            ))),
+            _ => Ok(()),
+        };
+    }
+
+    for _ in 0..MAX_ID_ATTEMPTS {
+        let id = generate_random_id();
+        if !taken(id)? {
+            fields.insert("id".to_string(), id.into());
+            return Ok(());
+        }
+    }
+    Err(AppError::Conflict("no free id left".to_string()))
+}
+
+fn push_item(
+    items: &mut Vec<serde_json::Value>,
+    mut new_item: serde_json::Value,
+) -> AppResult<serde_json::Value> {
+    assign_id(&mut new_item, |id| {
+        Ok(items.iter().any(|item| item_id(item) == Some(id)))
+    })?;
+
+    items.push(new_item.clone());
+    Ok(new_item)
+}
+
+/// Checks, before a conditional write, that item `id` of `items` is still
+/// the `expected` one. Missing items are left to the write to report.
+fn check_unchanged(
+    items: &[serde_json::Value],
+    id: u64,
+    expected: Option<&serde_json::Value>,
+) -> AppResult<()> {
+    let current = items.iter().find(|item| item_id(item) == Some(id));
+    match (current, expected) {
+        (Some(current), Some(expected)) if current != expected => Err(
+            AppError::PreconditionFailed("the item was changed by another request".to_string()),
+        ),
+        _ => Ok(()),
+    }
+}
+
+fn replace_item(
+    items: &mut [serde_json::Value],
+    id: u64,
+    updated_item: &serde_json::Value,
+) -> bool {
+    match items.iter_mut().find(|item| item_id(item) == Some(id)) {
+        Some(item) => {
+            *item = updated_item.clone();
+            true
+        }
+        None => false,
+    }
+}
+
+fn patch_item(
+    items: &mut [serde_json::Value],
+    id: u64,
+    patch: &serde_json::Value,
+) -> Option<serde_json::Value> {
+    let item = items.iter_mut().find(|item| item_id(item) == Some(id))?;
+    merge_patch(item, patch);
+    if let Some(fields) = item.as_object_mut() {
+        fields.insert("id".to_string(), id.into());
+    }
+    Some(item.clone())
+}
+
+fn remove_item(items: &mut Vec<serde_json::Value>, id: u64) -> bool {
+    let total = items.len();
+    items.retain(|item| item_id(item) != Some(id));
+    items.len() != total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn push_item_gives_new_items_a_free_id() {
+        let mut items = vec![json!({ "id": 1 })];
+        let item = push_item(&mut items, json!({ "title": "a" })).unwrap();
+        let id = item_id(&item).unwrap();
+        assert_ne!(id, 1);
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[1], item);
+    }
+
+    #[test]
+    fn push_item_rejects_ids_in_use() {
+        let mut items = vec![json!({ "id": 1, "title": "a" })];
+        assert!(matches!(
+            push_item(&mut items, json!({ "id": 1, "title": "b" })),
+            Err(AppError::Conflict(_))
+        ));
+        assert!(push_item(&mut items, json!({ "id": 2 })).is_ok());
+        assert_eq!(items.len(), 2);
+    }
+
+    #[test]
+    fn push_item_rejects_items_that_are_not_objects() {
+        for item in [json!([1, 2]), json!(5), json!("a"), json!(null)] {
+            assert!(matches!(
+                push_item(&mut Vec::new(), item),
+                Err(AppError::BadRequest(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn conditional_writes_need_the_expected_item() {
+        let storage = MemoryStorage::default();
+        let item = storage.insert("posts", json!({ "id": 1 })).await.unwrap();
+        let stale = json!({ "id": 1, "title": "a" });
+        let patch = json!({ "title": "b" });
+
+        for result in [
+            storage
+                .replace_if("posts", 1, Some(&stale), &patch)
+                .await
+                .map(|_| ()),
+            storage
+                .patch_if("posts", 1, Some(&stale), &patch)
+                .await
+                .map(|_| ()),
+            storage
+                .delete_if("posts", 1, Some(&stale))
+                .await
+                .map(|_| ()),
+        ] {
+            assert!(matches!(result, Err(AppError::PreconditionFailed(_))));
+        }
+        assert_eq!(storage.get("posts", 1).await.unwrap(), item);
+
+        let patched = storage.patch_if("posts", 1, Some(&item), &patch).await;
+        assert_eq!(patched.unwrap(), Some(json!({ "id": 1, "title": "b" })));
+        assert!(!storage.delete_if("posts", 2, Some(&item)).await.unwrap());
+    }
+
+    #[test]
+    fn assign_id_gives_up_when_every_id_is_taken() {
+        let mut item = json!({});
+        assert!(assign_id(&mut item, |_| Ok(true)).is_err());
+        assert!(item.get("id").is_none());
+    }
+}
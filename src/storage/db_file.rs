@@ -0,0 +1,160 @@
+use salvo::async_trait;
+use std::collections::BTreeMap;
+use tokio::sync::Mutex;
+
+use super::{
+    check_unchanged, find_item, patch_item, push_item, remove_item, replace_item, Storage,
+};
+use crate::error::AppResult;
+
+pub(super) const DB_FILE: &str = "db.json";
+
+type Collections = BTreeMap<String, Vec<serde_json::Value>>;
+
+/// Every collection in a single `db.json` object keyed by collection name.
+#[derive(Debug)]
+pub struct DbFileStorage {
+    data_dir: String,
+    file_path: String,
+    write_lock: Mutex<()>,
+}
+
+impl DbFileStorage {
+    pub fn new(data_dir: &str) -> Self {
+        Self {
+            data_dir: data_dir.to_string(),
+            file_path: format!("{}/{}", data_dir, DB_FILE),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    async fn read_db(&self) -> AppResult<Collections> {
+        match tokio::fs::read_to_string(&self.file_path).await {
+            Ok(json_string) => Ok(serde_json::from_str(&json_string)?),
+            Err(_) => Ok(Collections::new()),
+        }
+    }
+
+    async fn write_db(&self, collections: &Collections) -> AppResult<()> {
+        let json_string = serde_json::to_string_pretty(collections)?;
+        tokio::fs::write(&self.file_path, json_string).await?;
+        Ok(())
+    }
+
+    async fn read_or_create(&self, f: &str) -> AppResult<Vec<serde_json::Value>> {
+        let _guard = self.write_lock.lock().await;
+        let mut collections = self.read_db().await?;
+        if let Some(items) = collections.get(f) {
+            return Ok(items.clone());
+        }
+
+        collections.insert(f.to_string(), Vec::new());
+        self.write_db(&collections).await?;
+        Ok(Vec::new())
+    }
+}
+
+#[async_trait]
+impl Storage for DbFileStorage {
+    fn location(&self) -> Option<&str> {
+        Some(&self.data_dir)
+    }
+
+    async fn list_collections(&self) -> AppResult<Vec<String>> {
+        Ok(self.read_db().await?.into_keys().collect())
+    }
+
+    async fn get_all(&self, f: &str) -> AppResult<Vec<serde_json::Value>> {
+        self.read_or_create(f).await
+    }
+
+    async fn get(&self, f: &str, id: u64) -> AppResult<serde_json::Value> {
+        find_item(&self.read_or_create(f).await?, id)
+    }
+
+    async fn insert(&self, f: &str, new_item: serde_json::Value) -> AppResult<serde_json::Value> {
+        let _guard = self.write_lock.lock().await;
+        let mut collections = self.read_db().await?;
+        let new_item = push_item(collections.entry(f.to_string()).or_default(), new_item)?;
+        self.write_db(&collections).await?;
+        Ok(new_item)
+    }
+
+    async fn replace_if(
+        &self,
+        f: &str,
+        id: u64,
+        expected: Option<&serde_json::Value>,
+        updated_item: &serde_json::Value,
+    ) -> AppResult<bool> {
+        let _guard = self.write_lock.lock().await;
+        let mut collections = self.read_db().await?;
+        let Some(items) = collections.get_mut(f) else {
+            return Ok(false);
+        };
+        check_unchanged(items, id, expected)?;
+        let found_item = replace_item(items, id, updated_item);
+
+        if found_item {
+            self.write_db(&collections).await?;
+        }
+        Ok(found_item)
+    }
+
+    async fn patch_if(
+        &self,
+        f: &str,
+        id: u64,
+        expected: Option<&serde_json::Value>,
+        patch: &serde_json::Value,
+    ) -> AppResult<Option<serde_json::Value>> {
+        let _guard = self.write_lock.lock().await;
+        let mut collections = self.read_db().await?;
+        let Some(items) = collections.get_mut(f) else {
+            return Ok(None);
+        };
+        check_unchanged(items, id, expected)?;
+        let patched_item = patch_item(items, id, patch);
+
+        if patched_item.is_some() {
+            self.write_db(&collections).await?;
+        }
+        Ok(patched_item)
+    }
+
+    async fn delete_if(
+        &self,
+        f: &str,
+        id: u64,
+        expected: Option<&serde_json::Value>,
+    ) -> AppResult<bool> {
+        let _guard = self.write_lock.lock().await;
+        let mut collections = self.read_db().await?;
+        let Some(items) = collections.get_mut(f) else {
+            return Ok(false);
+        };
+        check_unchanged(items, id, expected)?;
+        let found_item = remove_item(items, id);
+
+        if found_item {
+            self.write_db(&collections).await?;
+        }
+        Ok(found_item)
+    }
+
+    async fn replace_collection(&self, f: &str, items: Vec<serde_json::Value>) -> AppResult<()> {
+        let _guard = self.write_lock.lock().await;
+        let mut collections = self.read_db().await?;
+        collections.insert(f.to_string(), items);
+        self.write_db(&collections).await
+    }
+
+    async fn delete_collection(&self, f: &str) -> AppResult<()> {
+        let _guard = self.write_lock.lock().await;
+        let mut collections = self.read_db().await?;
+        if collections.remove(f).is_some() {
+            self.write_db(&collections).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,334 @@
+use salvo::async_trait;
+use std::io;
+use std::path::PathBuf;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+use super::json_lines;
+use super::{
+    check_unchanged, find_item, into_items, is_schema_file, item_id, patch_item, push_item,
+    remove_item, replace_item, Storage,
+};
+use crate::error::AppResult;
+use crate::utils::convert_string_to_json;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Json,
+    JsonLines,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::JsonLines => "jsonl",
+        }
+    }
+}
+
+/// A collection as read from disk, before a change is written back.
+struct Collection {
+    format: Format,
+    items: Vec<serde_json::Value>,
+    lines: usize,
+}
+
+/// One file per collection inside `data_dir`: either a pretty-printed
+/// `{f}.json` array or an append-only `{f}.jsonl` log.
+///
+/// Existing files keep their format; new collections listed in `json_lines`
+/// (or all of them, with `*`) are created as JSON Lines.
+#[derive(Debug)]
+pub struct JsonFileStorage {
+    data_dir: String,
+    json_lines: Vec<String>,
+    write_lock: Mutex<()>,
+}
+
+impl JsonFileStorage {
+    pub fn new(data_dir: &str) -> Self {
+        Self {
+            data_dir: data_dir.to_string(),
+            json_lines: Vec::new(),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn with_json_lines(mut self, collections: Vec<String>) -> Self {
+        self.json_lines = collections;
+        self
+    }
+
+    fn file_path(&self, f: &str, format: Format) -> String {
+        format!("{}/{}.{}", self.data_dir, f, format.extension())
+    }
+
+    async fn format(&self, f: &str) -> Format {
+        for format in [Format::JsonLines, Format::Json] {
+            if tokio::fs::try_exists(self.file_path(f, format))
+                .await
+                .unwrap_or(false)
+            {
+                return format;
+            }
+        }
+
+        if self.json_lines.iter().any(|c| c == f || c == "*") {
+            Format::JsonLines
+        } else {
+            Format::Json
+        }
+    }
+
+    async fn read_json_from_file(&self, f: &str, format: Format) -> Result<String, io::Error> {
+        let mut json_file = tokio::fs::File::open(self.file_path(f, format)).await?;
+        let mut json_string = String::new();
+        json_file.read_to_string(&mut json_string).await?;
+        Ok(json_string)
+    }
+
+    async fn create_empty_json_file(&self, f: &str, format: Format) -> Result<(), io::Error> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(self.file_path(f, format))
+            .await?;
+        if format == Format::Json {
+            file.write_all(b"[]").await?;
+        }
+        // tokio finishes writes in the background unless flushed, which
+        // could land after a later write to the same file.
+        file.flush().await?;
+        Ok(())
+    }
+
+    fn parse(&self, f: &str, format: Format, json_string: &str) -> AppResult<Collection> {
+        let (items, lines) = match format {
+            Format::Json => {
+                let items = into_items(f, convert_string_to_json(json_string)?)?;
+                let lines = items.len();
+                (items, lines)
+            }
+            Format::JsonLines => json_lines::parse(json_string)?,
+        };
+        Ok(Collection {
+            format,
+            items,
+            lines,
+        })
+    }
+
+    async fn read_or_create(&self, f: &str) -> AppResult<Vec<serde_json::Value>> {
+        let format = self.format(f).await;
+        let json_string = match self.read_json_from_file(f, format).await {
+            Ok(s) => s,
+            Err(_) => {
+                self.create_empty_json_file(f, format).await?;
+                return Ok(Vec::new());
+            }
+        };
+        Ok(self.parse(f, format, &json_string)?.items)
+    }
+
+    async fn read_existing(&self, f: &str) -> AppResult<Option<Collection>> {
+        let format = self.format(f).await;
+        let json_string = match self.read_json_from_file(f, format).await {
+            Ok(s) => s,
+            Err(_) => return Ok(None),
+        };
+        self.parse(f, format, &json_string).map(Some)
+    }
+
+    async fn write_items(
+        &self,
+        f: &str,
+        format: Format,
+        items: &[serde_json::Value],
+    ) -> AppResult<()> {
+        let json_string = match format {
+            Format::Json => serde_json::to_string_pretty(items)?,
+            Format::JsonLines => json_lines::encode_lines(items)?,
+        };
+        tokio::fs::write(self.file_path(f, format), json_string).await?;
+        Ok(())
+    }
+
+    async fn append_line(&self, f: &str, line: &serde_json::Value) -> AppResult<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.file_path(f, Format::JsonLines))
+            .await?;
+        file.write_all(json_lines::encode_line(line)?.as_bytes())
+            .await?;
+        file.flush().await?;
+        Ok(())
+    }
+
+    /// Persists a change already applied to `collection.items`. JSON files
+    /// are rewritten whole, while JSON Lines logs get `line` appended unless
+    /// they are due for compaction.
+    async fn commit(
+        &self,
+        f: &str,
+        collection: &Collection,
+        line: &serde_json::Value,
+    ) -> AppResult<()> {
+        match collection.format {
+            Format::JsonLines
+                if !json_lines::needs_compaction(collection.lines + 1, collection.items.len()) =>
+            {
+                self.append_line(f, line).await
+            }
+            format => self.write_items(f, format, &collection.items).await,
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for JsonFileStorage {
+    fn location(&self) -> Option<&str> {
+        Some(&self.data_dir)
+    }
+
+    async fn list_collections(&self) -> AppResult<Vec<String>> {
+        let mut data_content = tokio::fs::read_dir(&self.data_dir).await?;
+        let mut collections = Vec::new();
+
+        while let Some(data_input) = data_content.next_entry().await? {
+            let path = PathBuf::from(data_input.file_name());
+            if !data_input.path().is_file()
+                || is_schema_file(&path)
+                || path
+                    .extension()
+                    .is_none_or(|ext| ext != "json" && ext != "jsonl")
+            {
+                continue;
+            }
+            if let Some(stem) = path.file_stem() {
+                collections.push(stem.to_string_lossy().into_owned());
+            }
+        }
+
+        collections.sort();
+        collections.dedup();
+        Ok(collections)
+    }
+
+    async fn get_all(&self, f: &str) -> AppResult<Vec<serde_json::Value>> {
+        self.read_or_create(f).await
+    }
+
+    async fn get(&self, f: &str, id: u64) -> AppResult<serde_json::Value> {
+        find_item(&self.read_or_create(f).await?, id)
+    }
+
+    async fn insert(&self, f: &str, new_item: serde_json::Value) -> AppResult<serde_json::Value> {
+        let _guard = self.write_lock.lock().await;
+        let format = self.format(f).await;
+        if format == Format::JsonLines {
+            json_lines::check_item(&new_item)?;
+        }
+
+        // JSON Lines logs are read too, a line with the id of an existing
+        // item replacing it when read back.
+        let mut collection = self.read_existing(f).await?.unwrap_or(Collection {
+            format,
+            items: Vec::new(),
+            lines: 0,
+        });
+        let new_item = push_item(&mut collection.items, new_item)?;
+        self.commit(f, &collection, &new_item).await?;
+        Ok(new_item)
+    }
+
+    async fn replace_if(
+        &self,
+        f: &str,
+        id: u64,
+        expected: Option<&serde_json::Value>,
+        updated_item: &serde_json::Value,
+    ) -> AppResult<bool> {
+        let _guard = self.write_lock.lock().await;
+        let Some(mut collection) = self.read_existing(f).await? else {
+            return Ok(false);
+        };
+        check_unchanged(&collection.items, id, expected)?;
+        if collection.format == Format::JsonLines {
+            json_lines::check_item(updated_item)?;
+        }
+
+        if !replace_item(&mut collection.items, id, updated_item) {
+            return Ok(false);
+        }
+
+        // A log line only supersedes the old one when it carries the same id.
+        if item_id(updated_item) == Some(id) {
+            self.commit(f, &collection, updated_item).await?;
+        } else {
+            self.write_items(f, collection.format, &collection.items)
+                .await?;
+        }
+        Ok(true)
+    }
+
+    async fn patch_if(
+        &self,
+        f: &str,
+        id: u64,
+        expected: Option<&serde_json::Value>,
+        patch: &serde_json::Value,
+    ) -> AppResult<Option<serde_json::Value>> {
+        let _guard = self.write_lock.lock().await;
+        let Some(mut collection) = self.read_existing(f).await? else {
+            return Ok(None);
+        };
+        check_unchanged(&collection.items, id, expected)?;
+
+        let patched_item = patch_item(&mut collection.items, id, patch);
+        if let Some(patched_item) = &patched_item {
+            if collection.format == Format::JsonLines {
+                json_lines::check_item(patched_item)?;
+            }
+            self.commit(f, &collection, patched_item).await?;
+        }
+        Ok(patched_item)
+    }
+
+    async fn delete_if(
+        &self,
+        f: &str,
+        id: u64,
+        expected: Option<&serde_json::Value>,
+    ) -> AppResult<bool> {
+        let _guard = self.write_lock.lock().await;
+        let Some(mut collection) = self.read_existing(f).await? else {
+            return Ok(false);
+        };
+        check_unchanged(&collection.items, id, expected)?;
+
+        let found_item = remove_item(&mut collection.items, id);
+        if found_item {
+            self.commit(f, &collection, &json_lines::tombstone(id))
+                .await?;
+        }
+        Ok(found_item)
+    }
+
+    async fn replace_collection(&self, f: &str, items: Vec<serde_json::Value>) -> AppResult<()> {
+        let _guard = self.write_lock.lock().await;
+        let format = self.format(f).await;
+        if format == Format::JsonLines {
+            items.iter().try_for_each(json_lines::check_item)?;
+        }
+        self.write_items(f, format, &items).await
+    }
+
+    async fn delete_collection(&self, f: &str) -> AppResult<()> {
+        tokio::fs::remove_file(self.file_path(f, self.format(f).await)).await?;
+        Ok(())
+    }
+}
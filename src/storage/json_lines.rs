@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+
+use super::item_id;
+use crate::error::{AppError, AppResult};
+
+/// Lines that no longer describe a live item are only rewritten away once
+/// there are at least this many of them and they outnumber the live items.
+const COMPACTION_MIN_STALE_LINES: usize = 100;
+
+const DELETED_FIELD: &str = "_deleted";
+
+/// Folds a `{f}.jsonl` file into its current items, also returning how many
+/// lines it holds.
+///
+/// The file is an append-only log: every line is a full item, a later line
+/// with the same `id` supersedes an earlier one, and `{"id": N, "_deleted": true}`
+/// removes item `N`. Items cannot have a `_deleted` field, see [`check_item`].
+pub(super) fn parse(json_lines: &str) -> AppResult<(Vec<serde_json::Value>, usize)> {
+    let mut items: Vec<Option<serde_json::Value>> = Vec::new();
+    let mut positions: HashMap<u64, usize> = HashMap::new();
+    let mut lines = 0;
+
+    for line in json_lines.lines().filter(|line| !line.trim().is_empty()) {
+        lines += 1;
+        let item: serde_json::Value = serde_json::from_str(line)?;
+
+        let Some(id) = item_id(&item) else {
+            items.push(Some(item));
+            continue;
+        };
+
+        let deleted = item[DELETED_FIELD].as_bool() == Some(true);
+        match positions.get(&id) {
+            Some(&position) if deleted => {
+                items[position] = None;
+                positions.remove(&id);
+            }
+            Some(&position) => items[position] = Some(item),
+            None if deleted => {}
+            None => {
+                positions.insert(id, items.len());
+                items.push(Some(item));
+            }
+        }
+    }
+
+    Ok((items.into_iter().flatten().collect(), lines))
+}
+
+/// Whether a log of `lines` lines describing `live_items` items is mostly
+/// stale and should be rewritten instead of appended to.
+pub(super) fn needs_compaction(lines: usize, live_items: usize) -> bool {
+    let stale_lines = lines.saturating_sub(live_items);
+    stale_lines >= COMPACTION_MIN_STALE_LINES && stale_lines > live_items
+}
+
+/// Rejects items with a `_deleted` field, which could read back as tombstones.
+pub(super) fn check_item(item: &serde_json::Value) -> AppResult<()> {
+    if item.get(DELETED_FIELD).is_some() {
+        return Err(AppError::BadRequest(format!(
+            "`{DELETED_FIELD}` is reserved in JSON Lines collections"
+        )));
+    }
+    Ok(())
+}
+
+pub(super) fn encode_line(item: &serde_json::Value) -> AppResult<String> {
+    Ok(format!("{}\n", serde_json::to_string(item)?))
+}
+
+pub(super) fn encode_lines(items: &[serde_json::Value]) -> AppResult<String> {
+    items.iter().map(encode_line).collect()
+}
+
+pub(super) fn tombstone(id: u64) -> serde_json::Value {
+    serde_json::json!({ "id": id, DELETED_FIELD: true })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parse_keeps_the_last_line_of_each_item() {
+        let log = "{\"id\":1,\"t\":\"a\"}\n{\"id\":2}\n\n{\"id\":1,\"t\":\"b\"}\n";
+        let (items, lines) = parse(log).unwrap();
+        assert_eq!(
+            items,
+            vec![json!({ "id": 1, "t": "b" }), json!({ "id": 2 })]
+        );
+        assert_eq!(lines, 3);
+    }
+
+    #[test]
+    fn parse_drops_deleted_items() {
+        let log = [
+            json!({ "id": 1 }),
+            json!({ "id": 2 }),
+            tombstone(1),
+            tombstone(3),
+            json!({ "t": "no id" }),
+        ];
+        let (items, lines) = parse(&encode_lines(&log).unwrap()).unwrap();
+        assert_eq!(items, vec![json!({ "id": 2 }), json!({ "t": "no id" })]);
+        assert_eq!(lines, 5);
+    }
+
+    #[test]
+    fn parse_brings_back_items_written_after_their_deletion() {
+        let log = [
+            json!({ "id": 1, "t": "a" }),
+            tombstone(1),
+            json!({ "id": 1, "t": "b" }),
+        ];
+        let (items, _) = parse(&encode_lines(&log).unwrap()).unwrap();
+        assert_eq!(items, vec![json!({ "id": 1, "t": "b" })]);
+    }
+
+    #[test]
+    fn parse_rejects_invalid_lines() {
+        assert!(parse("{\"id\":1}\nnot json\n").is_err());
+    }
+
+    #[test]
+    fn compacts_logs_mostly_made_of_stale_lines() {
+        assert!(!needs_compaction(0, 0));
+        assert!(!needs_compaction(99, 0));
+        assert!(needs_compaction(100, 0));
+        assert!(needs_compaction(250, 120));
+        assert!(!needs_compaction(250, 125));
+        assert!(!needs_compaction(1_000, 1_000));
+    }
+
+    #[test]
+    fn check_item_rejects_the_deleted_field() {
+        assert!(check_item(&json!({ "id": 1, "title": "a" })).is_ok());
+        assert!(check_item(&json!([1, 2])).is_ok());
+        assert!(check_item(&json!({ "id": 5, "_deleted": true })).is_err());
+        assert!(check_item(&json!({ "_deleted": false })).is_err());
+    }
+}
@@ -0,0 +1,145 @@
+use salvo::async_trait;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use super::db_file::DB_FILE;
+use super::json_lines;
+use super::{
+    check_unchanged, find_item, into_items, is_schema_file, patch_item, push_item, remove_item,
+    replace_item, Storage,
+};
+use crate::error::AppResult;
+use crate::utils::convert_string_to_json;
+
+/// Keeps every collection in memory and never writes back to disk.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    collections: Mutex<BTreeMap<String, Vec<serde_json::Value>>>,
+}
+
+impl MemoryStorage {
+    /// Seeds the store with every `{f}.json` or `{f}.jsonl` file found in
+    /// `data_dir`, plus the collections of a `db.json` file if there is one.
+    pub async fn load(data_dir: &str) -> AppResult<Self> {
+        let mut collections = BTreeMap::new();
+        let mut data_content = tokio::fs::read_dir(data_dir).await?;
+
+        while let Some(data_input) = data_content.next_entry().await? {
+            let path = data_input.path();
+            let Some(extension) = path
+                .extension()
+                .filter(|_| path.is_file() && !is_schema_file(&path))
+            else {
+                continue;
+            };
+            let Some(f) = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+            else {
+                continue;
+            };
+            let json_string = tokio::fs::read_to_string(&path).await?;
+
+            if data_input.file_name() == DB_FILE {
+                collections.extend(serde_json::from_str::<BTreeMap<_, _>>(&json_string)?);
+            } else if extension == "json" {
+                let items = into_items(&f, convert_string_to_json(&json_string)?)?;
+                collections.insert(f, items);
+            } else if extension == "jsonl" {
+                collections.insert(f, json_lines::parse(&json_string)?.0);
+            }
+        }
+
+        Ok(Self {
+            collections: Mutex::new(collections),
+        })
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    fn location(&self) -> Option<&str> {
+        None
+    }
+
+    async fn list_collections(&self) -> AppResult<Vec<String>> {
+        Ok(self.collections.lock().unwrap().keys().cloned().collect())
+    }
+
+    async fn get_all(&self, f: &str) -> AppResult<Vec<serde_json::Value>> {
+        let mut collections = self.collections.lock().unwrap();
+        Ok(collections.entry(f.to_string()).or_default().clone())
+    }
+
+    async fn get(&self, f: &str, id: u64) -> AppResult<serde_json::Value> {
+        let mut collections = self.collections.lock().unwrap();
+        find_item(collections.entry(f.to_string()).or_default(), id)
+    }
+
+    async fn insert(&self, f: &str, new_item: serde_json::Value) -> AppResult<serde_json::Value> {
+        let mut collections = self.collections.lock().unwrap();
+        push_item(collections.entry(f.to_string()).or_default(), new_item)
+    }
+
+    async fn replace_if(
+        &self,
+        f: &str,
+        id: u64,
+        expected: Option<&serde_json::Value>,
+        updated_item: &serde_json::Value,
+    ) -> AppResult<bool> {
+        let mut collections = self.collections.lock().unwrap();
+        let Some(items) = collections.get_mut(f) else {
+            return Ok(false);
+        };
+        check_unchanged(items, id, expected)?;
+        Ok(replace_item(items, id, updated_item))
+    }
+
+    async fn patch_if(
+        &self,
+        f: &str,
+        id: u64,
+        expected: Option<&serde_json::Value>,
+        patch: &serde_json::Value,
+    ) -> AppResult<Option<serde_json::Value>> {
+        let mut collections = self.collections.lock().unwrap();
+        let Some(items) = collections.get_mut(f) else {
+            return Ok(None);
+        };
+        check_unchanged(items, id, expected)?;
+        Ok(patch_item(items, id, patch))
+    }
+
+    async fn delete_if(
+        &self,
+        f: &str,
+        id: u64,
+        expected: Option<&serde_json::Value>,
+    ) -> AppResult<bool> {
+        let mut collections = self.collections.lock().unwrap();
+        let Some(items) = collections.get_mut(f) else {
+            return Ok(false);
+        };
+        check_unchanged(items, id, expected)?;
+        Ok(remove_item(items, id))
+    }
+
+    async fn count(&self, f: &str) -> AppResult<usize> {
+        let collections = self.collections.lock().unwrap();
+        Ok(collections.get(f).map_or(0, Vec::len))
+    }
+
+    async fn replace_collection(&self, f: &str, items: Vec<serde_json::Value>) -> AppResult<()> {
+        self.collections
+            .lock()
+            .unwrap()
+            .insert(f.to_string(), items);
+        Ok(())
+    }
+
+    async fn delete_collection(&self, f: &str) -> AppResult<()> {
+        self.collections.lock().unwrap().remove(f);
+        Ok(())
+    }
+}
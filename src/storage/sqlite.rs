@@ -0,0 +1,308 @@
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension};
+use salvo::async_trait;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use super::{assign_id, check_unchanged, Storage};
+use crate::error::{AppError, AppResult};
+use crate::utils::merge_patch;
+
+pub const SQLITE_FILE: &str = "db.sqlite";
+
+/// A SQLite database with one table per collection, each row holding an
+/// item as a JSON document next to its `id`, unique within the table.
+#[derive(Debug)]
+pub struct SqliteStorage {
+    data_dir: String,
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    pub fn open(data_dir: &str) -> AppResult<Self> {
+        let connection = Connection::open(format!("{}/{}", data_dir, SQLITE_FILE))?;
+        Ok(Self {
+            data_dir: data_dir.to_string(),
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Runs `query` on a blocking thread, SQLite calls being synchronous.
+    /// Writes giving an item the id of another one are conflicts.
+    async fn run<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&mut Connection) -> AppResult<T> + Send + 'static,
+    ) -> AppResult<T> {
+        let connection = self.connection.clone();
+        let result = tokio::task::spawn_blocking(move || query(&mut connection.lock().unwrap()))
+            .await
+            .map_err(io::Error::from)?;
+        match result {
+            Err(AppError::Sqlite(rusqlite::Error::SqliteFailure(error, _)))
+                if error.code == ErrorCode::ConstraintViolation =>
+            {
+                Err(AppError::Conflict(
+                    "another item has the same id".to_string(),
+                ))
+            }
+            result => result,
+        }
+    }
+}
+
+fn table(f: &str) -> String {
+    format!("\"{}\"", f.replace('"', "\"\""))
+}
+
+fn sql_id(item: &serde_json::Value) -> Option<i64> {
+    item["id"].as_u64().and_then(|id| i64::try_from(id).ok())
+}
+
+fn create_table(connection: &Connection, f: &str) -> AppResult<()> {
+    // SQLite names the index of `UNIQUE` with a prefix tables cannot have,
+    // so that it never takes the name of a collection.
+    connection.execute_batch(&format!(
+        "CREATE TABLE IF NOT EXISTS {name} (
+            seq INTEGER PRIMARY KEY AUTOINCREMENT,
+            id INTEGER UNIQUE,
+            doc TEXT NOT NULL
+        );",
+        name = table(f),
+    ))?;
+    Ok(())
+}
+
+fn table_exists(connection: &Connection, f: &str) -> AppResult<bool> {
+    let exists = connection
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            [f],
+            |_| Ok(()),
+        )
+        .optional()?;
+    Ok(exists.is_some())
+}
+
+fn select_doc(connection: &Connection, f: &str, id: u64) -> AppResult<Option<serde_json::Value>> {
+    let doc: Option<String> = connection
+        .query_row(
+            &format!(
+                "SELECT doc FROM {} WHERE id = ?1 ORDER BY seq LIMIT 1",
+                table(f)
+            ),
+            [id as i64],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(doc.map(|doc| serde_json::from_str(&doc)).transpose()?)
+}
+
+/// Checks that item `id` of `f` is still `expected`, when given, the
+/// connection lock keeping other writes out until the caller's own.
+fn check_doc(
+    connection: &Connection,
+    f: &str,
+    id: u64,
+    expected: Option<&serde_json::Value>,
+) -> AppResult<()> {
+    if expected.is_none() {
+        return Ok(());
+    }
+    check_unchanged(select_doc(connection, f, id)?.as_slice(), id, expected)
+}
+
+fn update_doc(
+    connection: &Connection,
+    f: &str,
+    id: u64,
+    updated_item: &serde_json::Value,
+) -> AppResult<bool> {
+    let changed = connection.execute(
+        &format!(
+            "UPDATE {name} SET id = ?1, doc = ?2
+             WHERE seq = (SELECT seq FROM {name} WHERE id = ?3 ORDER BY seq LIMIT 1)",
+            name = table(f)
+        ),
+        params![sql_id(updated_item), updated_item.to_string(), id as i64],
+    )?;
+    Ok(changed > 0)
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    fn location(&self) -> Option<&str> {
+        Some(&self.data_dir)
+    }
+
+    async fn list_collections(&self) -> AppResult<Vec<String>> {
+        self.run(|connection| {
+            let mut statement = connection.prepare(
+                "SELECT name FROM sqlite_master
+                 WHERE type = 'table' AND name NOT LIKE 'sqlite_%'
+                 ORDER BY name",
+            )?;
+            let collections = statement
+                .query_map([], |row| row.get(0))?
+                .collect::<Result<Vec<String>, _>>()?;
+            Ok(collections)
+        })
+        .await
+    }
+
+    async fn get_all(&self, f: &str) -> AppResult<Vec<serde_json::Value>> {
+        let f = f.to_string();
+        self.run(move |connection| {
+            create_table(connection, &f)?;
+
+            let mut statement =
+                connection.prepare(&format!("SELECT doc FROM {} ORDER BY seq", table(&f)))?;
+            let docs = statement
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            docs.iter()
+                .map(|doc| Ok(serde_json::from_str(doc)?))
+                .collect()
+        })
+        .await
+    }
+
+    async fn get(&self, f: &str, id: u64) -> AppResult<serde_json::Value> {
+        let f = f.to_string();
+        self.run(move |connection| {
+            create_table(connection, &f)?;
+            select_doc(connection, &f, id)?.ok_or(AppError::ItemNotFound(id))
+        })
+        .await
+    }
+
+    async fn insert(
+        &self,
+        f: &str,
+        mut new_item: serde_json::Value,
+    ) -> AppResult<serde_json::Value> {
+        let f = f.to_string();
+        self.run(move |connection| {
+            create_table(connection, &f)?;
+            assign_id(&mut new_item, |id| {
+                Ok(select_doc(connection, &f, id)?.is_some())
+            })?;
+            connection.execute(
+                &format!("INSERT INTO {} (id, doc) VALUES (?1, ?2)", table(&f)),
+                params![sql_id(&new_item), new_item.to_string()],
+            )?;
+            Ok(new_item)
+        })
+        .await
+    }
+
+    async fn replace_if(
+        &self,
+        f: &str,
+        id: u64,
+        expected: Option<&serde_json::Value>,
+        updated_item: &serde_json::Value,
+    ) -> AppResult<bool> {
+        let (f, expected, updated_item) = (f.to_string(), expected.cloned(), updated_item.clone());
+        self.run(move |connection| {
+            if !table_exists(connection, &f)? {
+                return Ok(false);
+            }
+            check_doc(connection, &f, id, expected.as_ref())?;
+            update_doc(connection, &f, id, &updated_item)
+        })
+        .await
+    }
+
+    async fn patch_if(
+        &self,
+        f: &str,
+        id: u64,
+        expected: Option<&serde_json::Value>,
+        patch: &serde_json::Value,
+    ) -> AppResult<Option<serde_json::Value>> {
+        let (f, expected, patch) = (f.to_string(), expected.cloned(), patch.clone());
+        self.run(move |connection| {
+            if !table_exists(connection, &f)? {
+                return Ok(None);
+            }
+            let Some(mut item) = select_doc(connection, &f, id)? else {
+                return Ok(None);
+            };
+            check_unchanged(std::slice::from_ref(&item), id, expected.as_ref())?;
+
+            merge_patch(&mut item, &patch);
+            if let Some(fields) = item.as_object_mut() {
+                fields.insert("id".to_string(), id.into());
+            }
+            update_doc(connection, &f, id, &item)?;
+            Ok(Some(item))
+        })
+        .await
+    }
+
+    async fn delete_if(
+        &self,
+        f: &str,
+        id: u64,
+        expected: Option<&serde_json::Value>,
+    ) -> AppResult<bool> {
+        let (f, expected) = (f.to_string(), expected.cloned());
+        self.run(move |connection| {
+            if !table_exists(connection, &f)? {
+                return Ok(false);
+            }
+            check_doc(connection, &f, id, expected.as_ref())?;
+            let changed = connection.execute(
+                &format!("DELETE FROM {} WHERE id = ?1", table(&f)),
+                [id as i64],
+            )?;
+            Ok(changed > 0)
+        })
+        .await
+    }
+
+    async fn count(&self, f: &str) -> AppResult<usize> {
+        let f = f.to_string();
+        self.run(move |connection| {
+            create_table(connection, &f)?;
+            let count: i64 = connection.query_row(
+                &format!("SELECT COUNT(*) FROM {}", table(&f)),
+                [],
+                |row| row.get(0),
+            )?;
+            Ok(count as usize)
+        })
+        .await
+    }
+
+    async fn replace_collection(&self, f: &str, items: Vec<serde_json::Value>) -> AppResult<()> {
+        let f = f.to_string();
+        self.run(move |connection| {
+            create_table(connection, &f)?;
+
+            let transaction = connection.transaction()?;
+            transaction.execute(&format!("DELETE FROM {}", table(&f)), [])?;
+            {
+                let mut statement = transaction.prepare(&format!(
+                    "INSERT INTO {} (id, doc) VALUES (?1, ?2)",
+                    table(&f)
+                ))?;
+                for item in &items {
+                    statement.execute(params![sql_id(item), item.to_string()])?;
+                }
+            }
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_collection(&self, f: &str) -> AppResult<()> {
+        let f = f.to_string();
+        self.run(move |connection| {
+            connection.execute(&format!("DROP TABLE IF EXISTS {}", table(&f)), [])?;
+            Ok(())
+        })
+        .await
+    }
+}
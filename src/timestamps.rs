@@ -0,0 +1,97 @@
+use serde::Deserialize;
+use time::format_description::BorrowedFormatItem;
+use time::macros::format_description;
+use time::OffsetDateTime;
+
+/// RFC 3339 in UTC with exactly three fractional digits, so that stamps sort
+/// like the times they stand for.
+const RFC3339_MILLIS: &[BorrowedFormatItem<'_>] =
+    format_description!("[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond digits:3]Z");
+
+/// Bookkeeping fields stamped on items when they are created and updated:
+/// creation and modification times, and a version bumped on every write.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timestamps {
+    pub created_at: String,
+    pub updated_at: String,
+    pub version: String,
+    pub format: TimestampFormat,
+}
+
+impl Default for Timestamps {
+    fn default() -> Self {
+        Self {
+            created_at: "createdAt".to_string(),
+            updated_at: "updatedAt".to_string(),
+            version: "version".to_string(),
+            format: TimestampFormat::default(),
+        }
+    }
+}
+
+/// How `created_at`/`updated_at` values are written.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampFormat {
+    /// An RFC 3339 string, e.g. `2024-05-01T12:00:00Z`.
+    #[default]
+    Rfc3339,
+    /// Seconds since the Unix epoch.
+    Unix,
+    /// Milliseconds since the Unix epoch.
+    UnixMs,
+}
+
+impl TimestampFormat {
+    /// The current time in this format.
+    pub fn now(self) -> serde_json::Value {
+        let now = OffsetDateTime::now_utc();
+        match self {
+            TimestampFormat::Rfc3339 => now.format(RFC3339_MILLIS).unwrap_or_default().into(),
+            TimestampFormat::Unix => now.unix_timestamp().into(),
+            TimestampFormat::UnixMs => ((now.unix_timestamp_nanos() / 1_000_000) as i64).into(),
+        }
+    }
+}
+
+impl Timestamps {
+    fn now(&self) -> serde_json::Value {
+        self.format.now()
+    }
+
+    /// Stamps an item about to be inserted as version 1.
+    pub fn stamp_new(&self, item: &mut serde_json::Value) {
+        let Some(fields) = item.as_object_mut() else {
+            return;
+        };
+        let now = self.now();
+        fields.insert(self.created_at.clone(), now.clone());
+        fields.insert(self.updated_at.clone(), now);
+        fields.insert(self.version.clone(), 1.into());
+    }
+
+    /// Stamps an item replacing `previous`, keeping its creation time, if
+    /// it has one.
+    pub fn stamp_replacement(&self, item: &mut serde_json::Value, previous: &serde_json::Value) {
+        self.stamp_patch(item, previous);
+        let (Some(fields), Some(created_at)) =
+            (item.as_object_mut(), previous.get(&self.created_at))
+        else {
+            return;
+        };
+        fields.insert(self.created_at.clone(), created_at.clone());
+    }
+
+    /// Adds the modification time and next version of `previous` to a merge
+    /// patch about to be applied to it, leaving its creation time alone.
+    pub fn stamp_patch(&self, patch: &mut serde_json::Value, previous: &serde_json::Value) {
+        let Some(fields) = patch.as_object_mut() else {
+            return;
+        };
+        fields.remove(&self.created_at);
+        let version = previous[self.version.as_str()].as_u64().unwrap_or(0) + 1;
+        fields.insert(self.updated_at.clone(), self.now());
+        fields.insert(self.version.clone(), version.into());
+    }
+}
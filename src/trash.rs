@@ -0,0 +1,18 @@
+use salvo::prelude::*;
+
+/// Field marking a soft-deleted item, holding the time it was deleted.
+pub const DELETED_AT: &str = "deletedAt";
+
+pub fn is_deleted(item: &serde_json::Value) -> bool {
+    !item[DELETED_AT].is_null()
+}
+
+/// Whether `?_withDeleted=true` asks for soft-deleted items too.
+pub fn with_deleted(req: &Request) -> bool {
+    req.query::<bool>("_withDeleted").unwrap_or(false)
+}
+
+/// Whether `?_purge=true` asks to remove a soft-deleted item for good.
+pub fn purge(req: &Request) -> bool {
+    req.query::<bool>("_purge").unwrap_or(false)
+}
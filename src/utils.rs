@@ -1,168 +1,95 @@
 use rand::RngExt;
-use std::io;
-use tokio::fs::OpenOptions;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-
-use crate::error::AppError;
 
 pub fn generate_random_id() -> u64 {
     let mut rng = rand::rng();
     rng.random_range(1..=100000)
 }
 
-pub async fn read_json_from_file(data_dir: &str, f: &str) -> Result<String, io::Error> {
-    let mut json_file = tokio::fs::File::open(format!("{}/{}.json", data_dir, f)).await?;
-    let mut json_string = String::new();
-    json_file.read_to_string(&mut json_string).await?;
-    Ok(json_string)
+pub fn convert_string_to_json(json_string: &str) -> Result<serde_json::Value, serde_json::Error> {
+    let json_value: serde_json::Value = serde_json::from_str(json_string)?;
+    Ok(json_value)
 }
 
-pub async fn get_item_by_id(
-    data_dir: &str,
-    file_path: &str,
-    id: u64,
-) -> Result<serde_json::Value, AppError> {
-    let json_string = match read_json_from_file(data_dir, file_path).await {
-        Ok(s) => s,
-        Err(_) => {
-            create_empty_json_file(data_dir, file_path).await?;
-            String::from("[]")
-        }
+/// Applies an RFC 7386 JSON merge patch: objects are merged recursively and
+/// `null` members remove the corresponding key.
+pub fn merge_patch(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    let Some(patch) = patch.as_object() else {
+        *target = patch.clone();
+        return;
     };
-    let json_value = convert_string_to_json(&json_string)?;
-
-    let filtered_item = json_value
-        .as_array()
-        .unwrap()
-        .iter()
-        .filter(|item| {
-            if let Some(item_id) = item["id"].as_u64() {
-                item_id == id
-            } else {
-                false
-            }
-        })
-        .cloned()
-        .collect::<Vec<serde_json::Value>>();
 
-    if let Some(filtered_item) = filtered_item.first() {
-        Ok(filtered_item.clone())
-    } else {
-        Err(AppError::ItemNotFound(id))
+    if !target.is_object() {
+        *target = serde_json::json!({});
     }
-}
-
-pub async fn create_empty_json_file(data_dir: &str, f: &str) -> Result<(), io::Error> {
-    let file_path = format!("{}/{}.json", data_dir, f);
-    let mut file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(file_path)
-        .await?;
-    file.write_all(b"[]").await?;
-    Ok(())
-}
 
-pub async fn add_item_to_json_file(
-    data_dir: &str,
-    file_name: &str,
-    mut new_item: serde_json::Value,
-) -> Result<serde_json::Value, AppError> {
-    let json_string = match read_json_from_file(data_dir, file_name).await {
-        Ok(s) => s,
-        Err(_) => {
-            create_empty_json_file(data_dir, file_name).await?;
-            String::from("[]")
+    let target = target.as_object_mut().unwrap();
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(
+                target.entry(key.clone()).or_insert(serde_json::Value::Null),
+                value,
+            );
         }
-    };
-    let mut json_value = convert_string_to_json(&json_string)?;
-
-    if new_item.get("id").is_none() {
-        new_item["id"] = serde_json::Value::from(generate_random_id());
     }
-
-    json_value.as_array_mut().unwrap().push(new_item.clone());
-
-    let json_string = serde_json::to_string_pretty(&json_value)?;
-    let file_path = format!("{}/{}.json", data_dir, file_name);
-    tokio::fs::write(file_path, json_string).await?;
-
-    Ok(new_item)
 }
 
-pub async fn update_json_file(
-    data_dir: &str,
-    f: &str,
-    id: u64,
-    updated_item: &serde_json::Value,
-) -> Result<bool, AppError> {
-    let file_path = format!("{}/{}.json", data_dir, f);
-    let json_string = match read_json_from_file(data_dir, f).await {
-        Ok(s) => s,
-        Err(_) => return Ok(false),
-    };
-
-    let mut json_value = convert_string_to_json(&json_string)?;
-    let mut found_item = false;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
 
-    if let Some(index) = json_value
-        .as_array()
-        .unwrap()
-        .iter()
-        .position(|item| item["id"].as_u64() == Some(id))
-    {
-        json_value.as_array_mut().unwrap()[index] = updated_item.clone();
-
-        let json_string = serde_json::to_string_pretty(&json_value)?;
-        tokio::fs::write(file_path, json_string).await?;
-        found_item = true;
+    fn merged(mut target: serde_json::Value, patch: serde_json::Value) -> serde_json::Value {
+        merge_patch(&mut target, &patch);
+        target
     }
-    Ok(found_item)
-}
-
-pub async fn delete_from_json_file(data_dir: &str, f: &str, id: u64) -> Result<bool, AppError> {
-    let file_path = format!("{}/{}.json", data_dir, f);
-    let json_string = match read_json_from_file(data_dir, f).await {
-        Ok(s) => s,
-        Err(_) => return Ok(false),
-    };
-
-    let json_value = convert_string_to_json(&json_string)?;
-    let mut found_item = false;
-
-    let filtered_items: Vec<serde_json::Value> = json_value
-        .as_array()
-        .unwrap()
-        .iter()
-        .filter(|item| {
-            if let Some(item_id) = item["id"].as_u64() {
-                if item_id != id {
-                    true
-                } else {
-                    found_item = true;
-                    false
-                }
-            } else {
-                true
-            }
-        })
-        .cloned()
-        .collect();
-
-    let json_string = serde_json::to_string_pretty(&filtered_items)?;
-    tokio::fs::write(file_path, json_string).await?;
-    Ok(found_item)
-}
 
-pub fn convert_string_to_json(json_string: &str) -> Result<serde_json::Value, serde_json::Error> {
-    let json_value: serde_json::Value = serde_json::from_str(json_string)?;
-    Ok(json_value)
-}
+    #[test]
+    fn merge_patch_adds_replaces_and_removes_members() {
+        assert_eq!(
+            merged(
+                json!({ "id": 1, "title": "a", "tags": ["x"], "draft": true }),
+                json!({ "title": "b", "tags": ["y", "z"], "draft": null, "views": 3 })
+            ),
+            json!({ "id": 1, "title": "b", "tags": ["y", "z"], "views": 3 })
+        );
+        assert_eq!(
+            merged(json!({ "a": 1 }), json!({ "b": null })),
+            json!({ "a": 1 })
+        );
+        assert_eq!(merged(json!({ "a": 1 }), json!({})), json!({ "a": 1 }));
+    }
 
-pub async fn delete_collection_sync(data_dir: &str, f: &str) -> Result<(), std::io::Error> {
-    let file_path = format!("{}/{}.json", data_dir, f);
-    tokio::fs::remove_file(file_path).await?;
+    #[test]
+    fn merge_patch_merges_nested_objects() {
+        assert_eq!(
+            merged(
+                json!({ "author": { "name": "Ann", "age": 30 } }),
+                json!({ "author": { "age": null, "city": "Oslo" } })
+            ),
+            json!({ "author": { "name": "Ann", "city": "Oslo" } })
+        );
+        assert_eq!(
+            merged(
+                json!({ "author": "Ann" }),
+                json!({ "author": { "name": "Ann" } })
+            ),
+            json!({ "author": { "name": "Ann" } })
+        );
+        assert_eq!(
+            merged(
+                json!({ "author": { "name": "Ann" } }),
+                json!({ "author": "Ann" })
+            ),
+            json!({ "author": "Ann" })
+        );
+    }
 
-    Ok(())
+    #[test]
+    fn merge_patch_replaces_the_target_with_other_patches() {
+        assert_eq!(merged(json!({ "a": 1 }), json!([1, 2])), json!([1, 2]));
+        assert_eq!(merged(json!({ "a": 1 }), json!(5)), json!(5));
+        assert_eq!(merged(json!([1]), json!({ "a": 1 })), json!({ "a": 1 }));
+    }
 }
@@ -0,0 +1,243 @@
+use salvo::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+
+use crate::changes::Change;
+use crate::error::{AppError, AppResult};
+use crate::timestamps::TimestampFormat;
+use crate::AppConfig;
+
+/// Attempts made to deliver one event before giving up.
+const MAX_ATTEMPTS: u32 = 4;
+
+/// Delay before the first retry, doubled for every later one.
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many delivery attempts are kept for `GET /_webhooks/deliveries`.
+const DELIVERY_LOG_CAPACITY: usize = 500;
+
+const EVENTS: [&str; 5] = ["created", "updated", "deleted", "restored", "reset"];
+
+/// A URL receiving a POST for every change made to `collection` (`*` for
+/// all of them), restricted to some `events` when given.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Webhook {
+    #[serde(default)]
+    pub id: u64,
+    pub collection: String,
+    pub url: String,
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+impl Webhook {
+    fn wants(&self, change: &Change) -> bool {
+        (self.collection == "*" || self.collection == change.collection)
+            && (self.events.is_empty() || self.events.iter().any(|e| e == change.operation.event()))
+    }
+}
+
+/// One attempt at delivering an event to a webhook.
+#[derive(Clone, Debug, Serialize)]
+struct Delivery {
+    webhook: u64,
+    url: String,
+    event: &'static str,
+    collection: String,
+    #[serde(rename = "itemId")]
+    item_id: Option<u64>,
+    attempt: u32,
+    timestamp: serde_json::Value,
+    status: Option<u16>,
+    error: Option<String>,
+    delivered: bool,
+}
+
+/// The registered webhooks and the log of their recent deliveries.
+#[derive(Debug)]
+pub struct Webhooks {
+    hooks: Mutex<Vec<Webhook>>,
+    deliveries: Mutex<VecDeque<Delivery>>,
+    client: reqwest::Client,
+}
+
+impl Webhooks {
+    pub fn new(hooks: Vec<Webhook>) -> AppResult<Self> {
+        let webhooks = Self {
+            hooks: Mutex::new(Vec::new()),
+            deliveries: Mutex::new(VecDeque::new()),
+            client: reqwest::Client::new(),
+        };
+        for hook in hooks {
+            webhooks.register(hook)?;
+        }
+        Ok(webhooks)
+    }
+
+    fn list(&self) -> Vec<Webhook> {
+        self.hooks.lock().unwrap().clone()
+    }
+
+    fn register(&self, mut hook: Webhook) -> AppResult<Webhook> {
+        if let Some(event) = hook.events.iter().find(|e| !EVENTS.contains(&e.as_str())) {
+            return Err(AppError::BadRequest(format!(
+                "unknown webhook event `{event}`: expected one of {}",
+                EVENTS.join(", ")
+            )));
+        }
+        if !hook.url.starts_with("http://") && !hook.url.starts_with("https://") {
+            return Err(AppError::BadRequest(format!(
+                "invalid webhook url `{}`",
+                hook.url
+            )));
+        }
+
+        let mut hooks = self.hooks.lock().unwrap();
+        hook.id = hooks.iter().map(|h| h.id).max().unwrap_or(0) + 1;
+        hooks.push(hook.clone());
+        Ok(hook)
+    }
+
+    fn unregister(&self, id: u64) -> bool {
+        let mut hooks = self.hooks.lock().unwrap();
+        let before = hooks.len();
+        hooks.retain(|hook| hook.id != id);
+        hooks.len() != before
+    }
+
+    fn log(&self, delivery: Delivery) {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        if deliveries.len() == DELIVERY_LOG_CAPACITY {
+            deliveries.pop_front();
+        }
+        deliveries.push_back(delivery);
+    }
+
+    /// Sends every change of the feed to the webhooks that want it, until the
+    /// feed closes.
+    pub async fn run(self: Arc<Self>, mut changes: Receiver<Change>) {
+        loop {
+            let change = match changes.recv().await {
+                Ok(change) => change,
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!("webhooks missed {missed} changes");
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            for hook in self.list().into_iter().filter(|hook| hook.wants(&change)) {
+                tokio::spawn(self.clone().deliver(hook, change.clone()));
+            }
+        }
+    }
+
+    /// POSTs `change` to `hook`, retrying with a growing delay until it is
+    /// accepted with a 2xx status or `MAX_ATTEMPTS` is reached.
+    async fn deliver(self: Arc<Self>, hook: Webhook, change: Change) {
+        let mut payload = change.payload();
+        payload["event"] = change.operation.event().into();
+
+        for attempt in 1..=MAX_ATTEMPTS {
+            let response = self
+                .client
+                .post(&hook.url)
+                .timeout(DELIVERY_TIMEOUT)
+                .json(&payload)
+                .send()
+                .await;
+            let (status, error) = match response {
+                Ok(response) if response.status().is_success() => {
+                    (Some(response.status().as_u16()), None)
+                }
+                Ok(response) => (
+                    Some(response.status().as_u16()),
+                    Some(format!("unexpected status {}", response.status())),
+                ),
+                Err(err) => (None, Some(err.to_string())),
+            };
+            let delivered = error.is_none();
+
+            self.log(Delivery {
+                webhook: hook.id,
+                url: hook.url.clone(),
+                event: change.operation.event(),
+                collection: change.collection.clone(),
+                item_id: change.id,
+                attempt,
+                timestamp: TimestampFormat::Rfc3339.now(),
+                status,
+                error,
+                delivered,
+            });
+            if delivered {
+                return;
+            }
+            if attempt < MAX_ATTEMPTS {
+                tokio::time::sleep(RETRY_DELAY * 2u32.pow(attempt - 1)).await;
+            }
+        }
+    }
+}
+
+#[handler]
+pub async fn list_webhooks(depot: &mut Depot) -> Json<Vec<Webhook>> {
+    let app_config = depot.obtain::<AppConfig>().unwrap();
+    Json(app_config.webhooks.list())
+}
+
+#[handler]
+pub async fn add_webhook(
+    req: &mut Request,
+    res: &mut Response,
+    depot: &mut Depot,
+) -> AppResult<Json<Webhook>> {
+    let app_config = depot.obtain::<AppConfig>().unwrap().clone();
+    let hook = req
+        .parse_body::<Webhook>()
+        .await
+        .map_err(|err| AppError::BadRequest(err.to_string()))?;
+
+    let hook = app_config.webhooks.register(hook)?;
+    res.status_code(StatusCode::CREATED);
+    Ok(Json(hook))
+}
+
+#[handler]
+pub async fn delete_webhook(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+    let app_config = depot.obtain::<AppConfig>().unwrap();
+    let id = req.param::<u64>("id").unwrap();
+
+    if app_config.webhooks.unregister(id) {
+        res.status_code(StatusCode::NO_CONTENT);
+    } else {
+        res.status_code(StatusCode::NOT_FOUND);
+        res.render(Json(serde_json::json!({})));
+    }
+}
+
+/// The most recent delivery attempts, oldest first, optionally only those
+/// of webhook `?webhook=<id>`.
+#[handler]
+pub async fn list_deliveries(req: &mut Request, depot: &mut Depot) -> Json<serde_json::Value> {
+    let app_config = depot.obtain::<AppConfig>().unwrap();
+    let webhook = req.query::<u64>("webhook");
+
+    let deliveries: Vec<Delivery> = app_config
+        .webhooks
+        .deliveries
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|delivery| webhook.is_none_or(|id| delivery.webhook == id))
+        .cloned()
+        .collect();
+    Json(serde_json::json!(deliveries))
+}
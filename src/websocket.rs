@@ -0,0 +1,330 @@
+use salvo::http::Method;
+use salvo::prelude::*;
+use salvo::websocket::{Message, WebSocket, WebSocketUpgrade};
+use serde::Deserialize;
+use std::collections::HashMap;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::auth::{caller, Caller};
+use crate::changes::Change;
+use crate::conditional::check_if_match_field;
+use crate::error::{AppError, AppResult};
+use crate::jwt::Claims;
+use crate::ops;
+use crate::rate_limit;
+use crate::AppConfig;
+
+/// A message sent by a WebSocket client. `ref` is echoed back in the reply
+/// so that clients can match replies with requests.
+#[derive(Debug, Deserialize)]
+struct ClientMessage {
+    #[serde(rename = "ref", default)]
+    reference: serde_json::Value,
+    #[serde(flatten)]
+    action: Action,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Action {
+    /// Receive the changes made to `collection`, only for items whose fields
+    /// equal those of `filter` when given.
+    Subscribe {
+        collection: String,
+        #[serde(default)]
+        filter: serde_json::Map<String, serde_json::Value>,
+    },
+    Unsubscribe {
+        collection: String,
+    },
+    List {
+        collection: String,
+    },
+    Get {
+        collection: String,
+        id: u64,
+    },
+    Create {
+        collection: String,
+        item: serde_json::Value,
+    },
+    /// Writes carry the `ETag` of the item they expect in `if_match`, as
+    /// requests do in their `If-Match` header.
+    Update {
+        collection: String,
+        id: u64,
+        item: serde_json::Value,
+        if_match: Option<String>,
+    },
+    Patch {
+        collection: String,
+        id: u64,
+        item: serde_json::Value,
+        if_match: Option<String>,
+    },
+    Delete {
+        collection: String,
+        id: u64,
+        if_match: Option<String>,
+    },
+}
+
+impl Action {
+    fn collection(&self) -> &str {
+        match self {
+            Action::Subscribe { collection, .. }
+            | Action::Unsubscribe { collection }
+            | Action::List { collection }
+            | Action::Get { collection, .. }
+            | Action::Create { collection, .. }
+            | Action::Update { collection, .. }
+            | Action::Patch { collection, .. }
+            | Action::Delete { collection, .. } => collection,
+        }
+    }
+
+    /// The HTTP method of the equivalent request, for the access rules.
+    fn method(&self) -> Method {
+        match self {
+            Action::Subscribe { .. }
+            | Action::Unsubscribe { .. }
+            | Action::List { .. }
+            | Action::Get { .. } => Method::GET,
+            Action::Create { .. } => Method::POST,
+            Action::Update { .. } => Method::PUT,
+            Action::Patch { .. } => Method::PATCH,
+            Action::Delete { .. } => Method::DELETE,
+        }
+    }
+}
+
+/// Field values a subscriber wants changed items to have, per collection.
+type Subscriptions = HashMap<String, serde_json::Map<String, serde_json::Value>>;
+
+#[handler]
+pub async fn connect(
+    req: &mut Request,
+    res: &mut Response,
+    depot: &mut Depot,
+) -> Result<(), StatusError> {
+    let app_config = depot.obtain::<AppConfig>().unwrap().clone();
+    let caller = caller(depot);
+    let client = rate_limit::client(req, &caller, app_config.trust_proxy);
+    WebSocketUpgrade::new()
+        .upgrade(req, res, move |ws| session(ws, app_config, caller, client))
+        .await
+}
+
+/// Answers the messages of one client while forwarding it the changes of the
+/// collections it subscribed to. Each message counts against the rate limit
+/// of `client` as a request would.
+async fn session(mut ws: WebSocket, app_config: AppConfig, caller: Caller, client: String) {
+    let mut changes = app_config.changes.subscribe();
+    let mut subscriptions = Subscriptions::new();
+
+    loop {
+        let outgoing = tokio::select! {
+            message = ws.recv() => {
+                let Some(Ok(message)) = message else {
+                    break;
+                };
+                if message.is_close() {
+                    break;
+                }
+                let Ok(text) = message.as_str() else {
+                    continue;
+                };
+                reply(&app_config, &caller, &client, &mut subscriptions, text).await
+            }
+            change = changes.recv() => match change {
+                Ok(change) => match notification(&app_config, caller.user(), &subscriptions, &change) {
+                    Some(notification) => notification,
+                    None => continue,
+                },
+                // Changes dropped while this session was busy are not
+                // replayed; the socket stays open.
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            },
+        };
+
+        if ws.send(Message::text(outgoing.to_string())).await.is_err() {
+            break;
+        }
+    }
+}
+
+fn notification(
+    app_config: &AppConfig,
+    user: Option<&Claims>,
+    subscriptions: &Subscriptions,
+    change: &Change,
+) -> Option<serde_json::Value> {
+    let filter = subscriptions.get(&change.collection)?;
+    let item = change.item();
+    let matches = filter
+        .iter()
+        .all(|(field, value)| item.is_some_and(|item| &item[field.as_str()] == value));
+    let foreign = item.is_some_and(|item| ops::foreign(app_config, &change.collection, item, user));
+    if !matches || foreign {
+        return None;
+    }
+
+    let mut notification = change.payload();
+    notification["type"] = change.operation.event().into();
+    Some(notification)
+}
+
+async fn reply(
+    app_config: &AppConfig,
+    caller: &Caller,
+    client: &str,
+    subscriptions: &mut Subscriptions,
+    text: &str,
+) -> serde_json::Value {
+    let message = match serde_json::from_str::<ClientMessage>(text) {
+        Ok(message) => message,
+        Err(err) => {
+            return serde_json::json!({ "type": "error", "ref": null, "message": err.to_string() });
+        }
+    };
+
+    match perform(app_config, caller, client, subscriptions, message.action).await {
+        Ok(data) => serde_json::json!({ "type": "result", "ref": message.reference, "data": data }),
+        Err(err) => serde_json::json!({
+            "type": "error",
+            "ref": message.reference,
+            "message": err.to_string(),
+        }),
+    }
+}
+
+async fn perform(
+    app_config: &AppConfig,
+    caller: &Caller,
+    client: &str,
+    subscriptions: &mut Subscriptions,
+    action: Action,
+) -> AppResult<serde_json::Value> {
+    let storage = app_config.storage.as_ref();
+    let user = caller.user();
+    let method = action.method();
+    app_config.rate_limiter.check(client, action.collection())?;
+    ops::check_access(app_config, action.collection(), &method, caller)?;
+    let collection = action.collection().to_string();
+    let mut data = match action {
+        Action::Subscribe { collection, filter } => {
+            subscriptions.insert(collection, filter);
+            Ok(serde_json::Value::Null)
+        }
+        Action::Unsubscribe { collection } => {
+            subscriptions.remove(&collection);
+            Ok(serde_json::Value::Null)
+        }
+        Action::List { collection } => {
+            let mut items = storage.get_all(&collection).await?;
+            items.retain(|item| {
+                !ops::hidden(app_config, item, false)
+                    && !ops::foreign(app_config, &collection, item, user)
+            });
+            for item in &mut items {
+                ops::redact(app_config, &collection, item);
+            }
+            Ok(items.into())
+        }
+        Action::Get { collection, id } => match storage.get(&collection, id).await? {
+            item if ops::hidden(app_config, &item, false) => Err(AppError::ItemNotFound(id)),
+            item => {
+                ops::check_owner(app_config, &collection, &method, Some(&item), user)?;
+                Ok(item)
+            }
+        },
+        Action::Create { collection, item } => {
+            ops::create(app_config, &collection, item, user).await
+        }
+        Action::Update {
+            collection,
+            id,
+            item,
+            if_match,
+        } => {
+            let previous = ops::current(app_config, &collection, id).await;
+            ops::check_owner(app_config, &collection, &method, previous.as_ref(), user)?;
+            let conditional =
+                check_condition(app_config, &collection, if_match, previous.as_ref())?;
+            ops::replace(
+                app_config,
+                &collection,
+                id,
+                item,
+                previous,
+                user,
+                conditional,
+            )
+            .await?
+            .ok_or(AppError::ItemNotFound(id))
+        }
+        Action::Patch {
+            collection,
+            id,
+            item,
+            if_match,
+        } => {
+            let current = ops::current(app_config, &collection, id).await;
+            ops::check_owner(app_config, &collection, &method, current.as_ref(), user)?;
+            let conditional = check_condition(app_config, &collection, if_match, current.as_ref())?;
+            ops::patch(
+                app_config,
+                &collection,
+                id,
+                item,
+                current,
+                user,
+                conditional,
+            )
+            .await?
+            .ok_or(AppError::ItemNotFound(id))
+        }
+        Action::Delete {
+            collection,
+            id,
+            if_match,
+        } => {
+            let current = storage.get(&collection, id).await.ok();
+            ops::check_owner(app_config, &collection, &method, current.as_ref(), user)?;
+            let conditional = check_condition(app_config, &collection, if_match, current.as_ref())?;
+            if ops::delete(
+                app_config,
+                &collection,
+                id,
+                current.as_ref(),
+                false,
+                conditional,
+            )
+            .await?
+            {
+                Ok(serde_json::Value::Null)
+            } else {
+                Err(AppError::ItemNotFound(id))
+            }
+        }
+    }?;
+    ops::redact(app_config, &collection, &mut data);
+    Ok(data)
+}
+
+/// Checks the `if_match` of a write against the `current` item of `f`, as
+/// clients see it. Returns whether the write is conditional.
+fn check_condition(
+    app_config: &AppConfig,
+    f: &str,
+    if_match: Option<String>,
+    current: Option<&serde_json::Value>,
+) -> AppResult<bool> {
+    let seen = current.map(|item| ops::redacted(app_config, f, item));
+    check_if_match_field(
+        if_match.as_deref(),
+        seen.as_ref(),
+        app_config.require_if_match,
+    )
+}